@@ -0,0 +1,40 @@
+use std::{
+	env,
+	path::Path,
+	process::Command,
+	time::SystemTime,
+};
+
+
+/// Expose build metadata for `--print-version-json` as environment variables
+fn main()
+{
+	let commit = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()
+		.filter(|out| out.status.success())
+		.and_then(|out| String::from_utf8(out.stdout).ok())
+		.map(|commit| commit.trim().to_owned())
+		.unwrap_or_default();
+	println!("cargo:rustc-env=CAMSCRUB_GIT_COMMIT={}", commit);
+
+	// honour reproducible builds
+	let timestamp = env::var("SOURCE_DATE_EPOCH").ok()
+		.and_then(|epoch| epoch.parse().ok())
+		.unwrap_or_else(|| SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs());
+	println!("cargo:rustc-env=CAMSCRUB_BUILD_TIMESTAMP={}", timestamp);
+
+	println!("cargo:rustc-env=CAMSCRUB_TARGET={}", env::var("TARGET").unwrap());
+
+	let mut features: Vec<_> = env::vars()
+		.filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+		.collect();
+	features.sort();
+	println!("cargo:rustc-env=CAMSCRUB_FEATURES={}", features.join(","));
+
+	// a missing file would rerun the script on every build
+	println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+	for path in [".git/HEAD", ".git/refs", "build.rs"] {
+		if Path::new(path).exists() {
+			println!("cargo:rerun-if-changed={}", path);
+		}
+	}
+}
@@ -0,0 +1,122 @@
+use std::{
+	fs, io::{self, Write},
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+
+use anyhow::{Result, Context};
+
+
+/// Paths of all images in `dir` ending with `suffix`, relative to `dir`
+///
+/// Hidden entries like the checkpoint or staging directory are skipped.
+pub fn scan(dir: &Path, suffix: &str) -> Result<Vec<PathBuf>>
+{
+	let mut imgs = Vec::new();
+	scan_into(dir, Path::new(""), suffix, &mut imgs)?;
+	Ok(imgs)
+}
+
+/// Latest modification time of the images in `dir` ending with `suffix`, if there are any
+pub fn newest_mtime(dir: &Path, suffix: &str) -> Result<Option<SystemTime>>
+{
+	let mut newest = None;
+	for rel in scan(dir, suffix)? {
+		let path = dir.join(rel);
+		let mtime = fs::metadata(&path).and_then(|md| md.modified())
+			.with_context(|| format!("failed to read modification time of {}", path.display()))?;
+		newest = newest.max(Some(mtime));
+	}
+	Ok(newest)
+}
+
+fn scan_into(base: &Path, rel: &Path, suffix: &str, imgs: &mut Vec<PathBuf>) -> Result<()>
+{
+	let dir = base.join(rel);
+	let entries = match fs::read_dir(&dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err)
+			.with_context(|| format!("failed to read directory {}", dir.display())),
+	};
+
+	for entry in entries {
+		let entry = entry?;
+		let name = entry.file_name();
+		let name_str = name.to_string_lossy();
+		if name_str.starts_with('.') {
+			continue;
+		}
+
+		let path = rel.join(&name);
+		if entry.file_type()?.is_dir() {
+			scan_into(base, &path, suffix, imgs)?;
+		} else if name_str.ends_with(suffix) {
+			imgs.push(path);
+		}
+	}
+	Ok(())
+}
+
+/// What is wrong with an archived JPEG, if anything
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JpegCheck {
+	Valid,
+	Empty,
+	/// Missing the JPEG start marker
+	NotJpeg,
+	/// Missing the JPEG end marker
+	Truncated,
+	/// Has both markers, but does not decode
+	Corrupt,
+}
+
+/// Check that the file at `path` is a complete JPEG by its markers, and with `decode` also decodable
+pub fn check_jpeg(path: &Path, decode: bool) -> Result<JpegCheck>
+{
+	let data = fs::read(path)
+		.with_context(|| format!("failed to read {}", path.display()))?;
+	Ok(if data.is_empty() {
+		JpegCheck::Empty
+	} else if !data.starts_with(&[0xff, 0xd8]) {
+		JpegCheck::NotJpeg
+	} else if !data.ends_with(&[0xff, 0xd9]) {
+		JpegCheck::Truncated
+	} else if decode && image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).is_err() {
+		JpegCheck::Corrupt
+	} else {
+		JpegCheck::Valid
+	})
+}
+
+/// Compression of a tarball written by `pack()`
+#[derive(Clone, Copy)]
+pub enum Compression {
+	Gzip,
+	Zstd,
+}
+
+/// Write `files` (relative to `dir`) into a compressed tarball at `out`, keeping their modification times
+pub fn pack(dir: &Path, files: &[PathBuf], out: &Path, compression: Compression) -> Result<()>
+{
+	let file = fs::File::create(out)
+		.with_context(|| format!("failed to create {}", out.display()))?;
+	let file = match compression {
+		Compression::Gzip => write_tar(dir, files, flate2::write::GzEncoder::new(file, flate2::Compression::default()))?
+			.finish(),
+		Compression::Zstd => write_tar(dir, files, zstd::Encoder::new(file, 0)?)?
+			.finish(),
+	};
+	file.and_then(|file| file.sync_all())
+		.with_context(|| format!("failed to write {}", out.display()))
+}
+
+fn write_tar<W: Write>(dir: &Path, files: &[PathBuf], writer: W) -> Result<W>
+{
+	let mut tar = tar::Builder::new(writer);
+	for rel in files {
+		tar.append_path_with_name(dir.join(rel), rel)
+			.with_context(|| format!("failed to add {} to the tarball", rel.display()))?;
+	}
+	Ok(tar.into_inner()?)
+}
@@ -0,0 +1,75 @@
+use anyhow::{Result, Context};
+use serde::Deserialize;
+use tokio::{sync::Mutex, time};
+
+use reqwest as http;
+
+
+/// Refresh tokens this long before they expire
+const REFRESH_MARGIN: time::Duration = time::Duration::from_secs(30);
+
+/// Limit on a token request, as the client has none on whole requests with --body-timeout
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Bearer token source using the OAuth2 client credentials grant
+pub struct OAuth {
+	client: http::Client,
+	token_url: http::Url,
+	client_id: String,
+	client_secret: String,
+	token: Mutex<Option<Token>>,
+}
+
+struct Token {
+	access_token: String,
+	expires: Option<time::Instant>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	access_token: String,
+	expires_in: Option<u64>,
+}
+
+impl OAuth
+{
+	pub fn new(client: http::Client, token_url: http::Url, client_id: String, client_secret: String) -> Self
+	{
+		OAuth { client, token_url, client_id, client_secret, token: Mutex::new(None) }
+	}
+
+	/// Current access token, refreshed if it is about to expire
+	pub async fn token(&self) -> Result<String>
+	{
+		let mut token = self.token.lock().await;
+		let fresh = match token.as_ref().map(|token| token.expires) {
+			Some(Some(expires)) => time::Instant::now() + REFRESH_MARGIN < expires,
+			Some(None) => true,
+			None => false,
+		};
+		if !fresh {
+			*token = Some(self.request().await
+				.with_context(|| format!("failed to get OAuth2 token from {}", self.token_url))?);
+		}
+		Ok(token.as_ref().unwrap().access_token.clone())
+	}
+
+	async fn request(&self) -> Result<Token>
+	{
+		let now = time::Instant::now();
+		let res = self.client.post(self.token_url.clone())
+			.basic_auth(&self.client_id, Some(&self.client_secret))
+			.form(&[("grant_type", "client_credentials")])
+			.timeout(REQUEST_TIMEOUT)
+			.send()
+			.await.context("failed to send token request")?
+			.error_for_status()?
+			.json::<TokenResponse>()
+			.await.context("failed to parse token response")?;
+
+		Ok(Token {
+			access_token: res.access_token,
+			expires: res.expires_in.map(|secs| now + time::Duration::from_secs(secs)),
+		})
+	}
+}
@@ -0,0 +1,241 @@
+use std::{
+	collections::{BTreeSet, HashSet, VecDeque},
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+
+/// Progress of a listing walk that survives restarts
+#[derive(Serialize, Deserialize, Default)]
+pub struct Checkpoint {
+	/// Listing cursor up to which all images are done
+	pub cursor: String,
+	/// Images downloaded (or found unchanged) so far
+	pub done: BTreeSet<String>,
+}
+
+/// A listed page whose images might still be in flight
+struct Page {
+	/// Cursor for the page after this one
+	cursor: String,
+	imgs: Vec<String>,
+}
+
+/// Tracks finished images of a run and periodically saves them as a checkpoint
+pub struct Tracker {
+	path: PathBuf,
+	state: Mutex<(Checkpoint, VecDeque<Page>)>,
+}
+
+impl Tracker
+{
+	pub async fn load(path: &Path) -> Result<Self>
+	{
+		let checkpoint = match fs::read(path).await {
+			// cut off by a crash before writes were atomic, or edited by hand
+			Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+				eprintln!("ignoring corrupt checkpoint {}, starting over: {}", path.display(), err);
+				Checkpoint::default()
+			}),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => Checkpoint::default(),
+			Err(err) => return Err(err)
+				.with_context(|| format!("failed to read checkpoint {}", path.display())),
+		};
+
+		Ok(Tracker {
+			path: path.to_owned(),
+			state: Mutex::new((checkpoint, VecDeque::new())),
+		})
+	}
+
+	/// Cursor to resume the listing walk from
+	pub fn cursor(&self) -> String
+	{
+		self.state.lock().unwrap().0.cursor.clone()
+	}
+
+	pub fn is_done(&self, img: &str) -> bool
+	{
+		self.state.lock().unwrap().0.done.contains(img)
+	}
+
+	pub fn finish(&self, img: &str)
+	{
+		self.state.lock().unwrap().0.done.insert(img.to_owned());
+	}
+
+	/// Remember a dispatched page and the cursor following it
+	pub fn push_page(&self, cursor: &str, imgs: &[String])
+	{
+		self.state.lock().unwrap().1.push_back(Page { cursor: cursor.to_owned(), imgs: imgs.to_vec() });
+	}
+
+	/// Advance the cursor past all completed pages and write the checkpoint
+	pub async fn save(&self) -> Result<()>
+	{
+		let data = {
+			let (checkpoint, pages) = &mut *self.state.lock().unwrap();
+			while let Some(page) = pages.front() {
+				if !page.imgs.iter().all(|img| checkpoint.done.contains(img)) {
+					break;
+				}
+				checkpoint.cursor = pages.pop_front().unwrap().cursor;
+			}
+			serde_json::to_vec(checkpoint)?
+		};
+
+		crate::state::write(&self.path, data).await
+			.with_context(|| format!("failed to write checkpoint {}", self.path.display()))
+	}
+}
+
+/// End of the contiguous range of an oldest-first backfill that survives restarts
+#[derive(Serialize, Deserialize, Default)]
+struct Watermark {
+	/// Newest image up to which all older listed images are done
+	upto: String,
+}
+
+/// Tracks images dispatched oldest first and saves how far they are done without gaps
+pub struct Backfill {
+	path: PathBuf,
+	/// Watermark, images in dispatch order after it, and those of them that are done
+	state: Mutex<(Watermark, VecDeque<String>, HashSet<String>)>,
+}
+
+impl Backfill
+{
+	pub async fn load(path: &Path) -> Result<Self>
+	{
+		let watermark = match fs::read(path).await {
+			Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+				eprintln!("ignoring corrupt backfill checkpoint {}, starting over: {}", path.display(), err);
+				Watermark::default()
+			}),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => Watermark::default(),
+			Err(err) => return Err(err)
+				.with_context(|| format!("failed to read backfill checkpoint {}", path.display())),
+		};
+
+		Ok(Backfill {
+			path: path.to_owned(),
+			state: Mutex::new((watermark, VecDeque::new(), HashSet::new())),
+		})
+	}
+
+	/// Whether `img` is newer than the watermark; ids are taken to sort like their capture times
+	pub fn is_pending(&self, img: &str) -> bool
+	{
+		img > self.state.lock().unwrap().0.upto.as_str()
+	}
+
+	/// Remember images in the order they are handed to the workers
+	pub fn dispatch(&self, imgs: &[String])
+	{
+		self.state.lock().unwrap().1.extend(imgs.iter().cloned());
+	}
+
+	/// Mark a dispatched image as done, moving the watermark over all images done before it;
+	/// failed images are never done, so the watermark stops in front of them
+	pub fn finish(&self, img: &str)
+	{
+		let (watermark, order, done) = &mut *self.state.lock().unwrap();
+		done.insert(img.to_owned());
+		while order.front().is_some_and(|img| done.contains(img)) {
+			let img = order.pop_front().unwrap();
+			done.remove(&img);
+			watermark.upto = img;
+		}
+	}
+
+	pub async fn save(&self) -> Result<()>
+	{
+		let data = serde_json::to_vec(&self.state.lock().unwrap().0)?;
+		crate::state::write(&self.path, data).await
+			.with_context(|| format!("failed to write backfill checkpoint {}", self.path.display()))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn imgs(ids: &[&str]) -> Vec<String>
+	{
+		ids.iter().map(|&id| id.to_owned()).collect()
+	}
+
+	#[tokio::test]
+	async fn tracker_round_trip()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("checkpoint.json");
+
+		let tracker = Tracker::load(&path).await.unwrap();
+		assert_eq!(tracker.cursor(), "");
+		tracker.push_page("b", &imgs(&["c", "d"]));
+		tracker.push_page("a", &imgs(&["b"]));
+		tracker.finish("c");
+		tracker.finish("d");
+		tracker.save().await.unwrap();
+
+		// the second page is not done, so the cursor stops after the first
+		let tracker = Tracker::load(&path).await.unwrap();
+		assert_eq!(tracker.cursor(), "b");
+		assert!(tracker.is_done("c") && tracker.is_done("d"));
+		assert!(!tracker.is_done("b"));
+	}
+
+	#[tokio::test]
+	async fn tracker_starts_over_on_corrupt_checkpoint()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("checkpoint.json");
+
+		let tracker = Tracker::load(&path).await.unwrap();
+		tracker.push_page("b", &imgs(&["c"]));
+		tracker.finish("c");
+		tracker.save().await.unwrap();
+
+		let data = fs::read(&path).await.unwrap();
+		fs::write(&path, &data[..data.len() / 2]).await.unwrap();
+		let tracker = Tracker::load(&path).await.unwrap();
+		assert_eq!(tracker.cursor(), "");
+		assert!(!tracker.is_done("c"));
+
+		fs::write(&path, "not json").await.unwrap();
+		assert_eq!(Tracker::load(&path).await.unwrap().cursor(), "");
+	}
+
+	#[tokio::test]
+	async fn backfill_round_trip()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("backfill.json");
+
+		let backfill = Backfill::load(&path).await.unwrap();
+		backfill.dispatch(&imgs(&["a", "b", "c"]));
+		backfill.finish("a");
+		backfill.finish("c");
+		backfill.save().await.unwrap();
+
+		// b failed, so the watermark stops in front of it
+		let backfill = Backfill::load(&path).await.unwrap();
+		assert!(!backfill.is_pending("a"));
+		assert!(backfill.is_pending("b") && backfill.is_pending("c"));
+	}
+
+	#[tokio::test]
+	async fn backfill_starts_over_on_corrupt_checkpoint()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("backfill.json");
+		fs::write(&path, r#"{"upto":"#).await.unwrap();
+		assert!(Backfill::load(&path).await.unwrap().is_pending("a"));
+	}
+}
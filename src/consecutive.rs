@@ -0,0 +1,158 @@
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use anyhow::{Result, Context};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::watch};
+
+
+/// What the frame after a finished one is compared with
+pub enum Content {
+	/// SHA-256 of a download, including one skipped as identical
+	Hash(String),
+	/// Archived before, hashed only if the frame after it needs it
+	File(PathBuf),
+	/// Failed or not handled, so the frame after it is kept
+	Unknown,
+}
+
+/// Frames in the order they are handed to the workers, each kept until the frame after it is done
+pub struct Frames {
+	/// List of skipped frames and the frame each repeats
+	list: PathBuf,
+	state: Mutex<State>,
+	changed: watch::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+	last: Option<String>,
+	before: HashMap<String, String>,
+	done: HashMap<String, Content>,
+}
+
+impl Frames
+{
+	pub fn new(list: &Path) -> Self
+	{
+		Frames {
+			list: list.to_owned(),
+			state: Mutex::new(State::default()),
+			changed: watch::channel(()).0,
+		}
+	}
+
+	/// Remember the predecessor of each image, in the order the images are sent to the workers
+	pub fn dispatch(&self, imgs: &[String])
+	{
+		let mut state = self.state.lock().unwrap();
+		for img in imgs {
+			if let Some(last) = state.last.replace(img.clone()) {
+				state.before.insert(img.clone(), last);
+			}
+		}
+	}
+
+	/// The frame before `img` if its content hashes to `sha256`, waiting for it to be done
+	pub async fn repeats(&self, img: &str, sha256: &str) -> Result<Option<String>>
+	{
+		let mut changed = self.changed.subscribe();
+		let (before, file) = loop {
+			{
+				let state = self.state.lock().unwrap();
+				let before = match state.before.get(img) {
+					Some(before) => before.clone(),
+					None => return Ok(None),
+				};
+				match state.done.get(&before) {
+					Some(Content::Hash(hash)) => return Ok((hash == sha256).then_some(before)),
+					Some(Content::File(file)) => break (before, file.clone()),
+					Some(Content::Unknown) => return Ok(None),
+					None => (),
+				}
+			}
+			// the sender lives as long as `self`
+			changed.changed().await.ok();
+		};
+
+		let data = match fs::read(&file).await {
+			Ok(data) => data,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err)
+				.with_context(|| format!("failed to read {}", file.display())),
+		};
+		Ok((format!("{:x}", Sha256::digest(&data)) == sha256).then_some(before))
+	}
+
+	/// Record the content of a frame that is done, forgetting its predecessor
+	pub fn finish(&self, img: &str, content: Content)
+	{
+		let mut state = self.state.lock().unwrap();
+		if let Some(before) = state.before.remove(img) {
+			state.done.remove(&before);
+		}
+		state.done.insert(img.to_owned(), content);
+		drop(state);
+		self.changed.send_replace(());
+	}
+
+	/// Add a skipped frame to the list, which keeps later runs from downloading it again
+	pub async fn skip(&self, img: &str, before: &str) -> Result<()>
+	{
+		let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.list).await
+			.with_context(|| format!("failed to open {}", self.list.display()))?;
+		file.write_all(format!("{}\t{}\n", img, before).as_bytes()).await
+			.with_context(|| format!("failed to write {}", self.list.display()))?;
+		// a tokio file only writes in the background otherwise
+		file.flush().await
+			.with_context(|| format!("failed to write {}", self.list.display()))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::sync::Arc;
+
+	fn hash(data: &[u8]) -> String
+	{
+		format!("{:x}", Sha256::digest(data))
+	}
+
+	#[tokio::test]
+	async fn frames_are_compared_with_the_one_before()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let archived = dir.path().join("a.jpg");
+		fs::write(&archived, b"same").await.unwrap();
+		let frames = Arc::new(Frames::new(&dir.path().join("identical.tsv")));
+		frames.dispatch(&["a".to_owned(), "b".to_owned()]);
+		frames.dispatch(&["c".to_owned(), "d".to_owned()]);
+
+		// b waits until its predecessor is done
+		let same = hash(b"same");
+		let wait = tokio::time::timeout(std::time::Duration::from_millis(50), frames.repeats("b", &same));
+		assert!(wait.await.is_err());
+		let waiting = tokio::spawn({
+			let frames = frames.clone();
+			async move { frames.repeats("b", &hash(b"same")).await.unwrap() }
+		});
+		tokio::task::yield_now().await;
+		frames.finish("a", Content::File(archived));
+		assert_eq!(waiting.await.unwrap().as_deref(), Some("a"));
+
+		frames.finish("b", Content::Hash(hash(b"same")));
+		assert_eq!(frames.repeats("c", &hash(b"other")).await.unwrap(), None);
+		frames.finish("c", Content::Unknown);
+		assert_eq!(frames.repeats("d", &hash(b"same")).await.unwrap(), None);
+		// the first frame has nothing to repeat
+		assert_eq!(frames.repeats("a", &hash(b"same")).await.unwrap(), None);
+
+		frames.skip("b", "a").await.unwrap();
+		assert_eq!(fs::read_to_string(dir.path().join("identical.tsv")).await.unwrap(), "b\ta\n");
+	}
+}
@@ -0,0 +1,92 @@
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use anyhow::{Result, Context};
+use tokio::fs;
+
+
+/// Content hashes of archived images and the file each was first saved as, kept across runs
+pub struct Index {
+	path: PathBuf,
+	/// Folder the indexed files are relative to
+	base: PathBuf,
+	hashes: Mutex<BTreeMap<String, PathBuf>>,
+}
+
+impl Index
+{
+	pub async fn load(path: &Path, base: &Path) -> Result<Self>
+	{
+		let hashes = match fs::read_to_string(path).await {
+			Ok(data) => data.lines()
+				.filter_map(|line| line.split_once('\t'))
+				// a damaged line must not link unrelated images
+				.filter(|(sha256, _)| sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit()))
+				.map(|(sha256, file)| (sha256.to_owned(), PathBuf::from(file)))
+				.collect(),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+			Err(err) => return Err(err)
+				.with_context(|| format!("failed to read hash index {}", path.display())),
+		};
+
+		Ok(Index {
+			path: path.to_owned(),
+			base: base.to_owned(),
+			hashes: Mutex::new(hashes),
+		})
+	}
+
+	/// Replace `file` with a hard link to an earlier file of the same content, returning whether it did;
+	/// without one, `file` becomes the file of that content
+	pub async fn link(&self, sha256: &str, file: &Path) -> Result<bool>
+	{
+		let rel = file.strip_prefix(&self.base).unwrap_or(file).to_owned();
+		let earlier = {
+			let mut hashes = self.hashes.lock().unwrap();
+			match hashes.get(sha256) {
+				Some(earlier) if *earlier != rel => self.base.join(earlier),
+				Some(_) => return Ok(false),
+				None => {
+					hashes.insert(sha256.to_owned(), rel);
+					return Ok(false);
+				},
+			}
+		};
+
+		// the earlier file may have been removed or changed since
+		let same = match (fs::read(&earlier).await, fs::read(file).await) {
+			(Ok(a), Ok(b)) => a == b,
+			(Err(err), _) if err.kind() == std::io::ErrorKind::NotFound => false,
+			(Err(err), _) => return Err(err)
+				.with_context(|| format!("failed to read {}", earlier.display())),
+			(_, Err(err)) => return Err(err)
+				.with_context(|| format!("failed to read {}", file.display())),
+		};
+		if !same {
+			self.hashes.lock().unwrap().insert(sha256.to_owned(), rel);
+			return Ok(false);
+		}
+
+		let mut tmp = file.as_os_str().to_owned();
+		tmp.push(".link");
+		let tmp = PathBuf::from(tmp);
+		fs::remove_file(&tmp).await.ok();
+		fs::hard_link(&earlier, &tmp).await
+			.with_context(|| format!("failed to link {} to {}", tmp.display(), earlier.display()))?;
+		fs::rename(&tmp, file).await
+			.with_context(|| format!("failed to move link into place at {}", file.display()))?;
+		Ok(true)
+	}
+
+	pub async fn save(&self) -> Result<()>
+	{
+		let data: String = self.hashes.lock().unwrap().iter()
+			.map(|(sha256, file)| format!("{}\t{}\n", sha256, file.display()))
+			.collect();
+		crate::state::write(&self.path, data).await
+			.with_context(|| format!("failed to write hash index {}", self.path.display()))
+	}
+}
@@ -0,0 +1,33 @@
+use std::{
+	fs::File,
+	os::unix::io::AsRawFd,
+	path::Path,
+};
+
+use anyhow::{Result, Context};
+use nix::{errno::Errno, fcntl::{flock, FlockArg}};
+
+
+/// Exclusive lock on a download directory, released when dropped or the process ends
+pub struct Lock {
+	_file: File,
+}
+
+impl Lock
+{
+	/// Take the lock at `path`, waiting for another instance to release it if `wait` is set
+	pub async fn acquire(path: &Path, wait: bool) -> Result<Self>
+	{
+		let file = File::create(path)
+			.with_context(|| format!("failed to create lock file {}", path.display()))?;
+
+		let arg = if wait { FlockArg::LockExclusive } else { FlockArg::LockExclusiveNonblock };
+		let fd = file.as_raw_fd();
+		match tokio::task::spawn_blocking(move || flock(fd, arg)).await? {
+			Ok(()) => Ok(Lock { _file: file }),
+			Err(Errno::EWOULDBLOCK) => anyhow::bail!("another instance is using {}, see --wait-for-lock", path.display()),
+			Err(err) => Err(err)
+				.with_context(|| format!("failed to lock {}", path.display())),
+		}
+	}
+}
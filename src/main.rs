@@ -2,9 +2,10 @@
 #![allow(unused_variables)]
 
 use std::{
-	collections::BTreeSet,
+	collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
 	path::{Path, PathBuf},
-	str::FromStr, sync::Arc
+	str::FromStr,
+	sync::{Arc, Mutex, atomic::{AtomicU32, AtomicU64, Ordering}},
 };
 
 use anyhow::{Result, Context};
@@ -16,8 +17,8 @@ use tokio::{
 	io::AsyncWriteExt,
 };
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 use futures_util::future::TryFutureExt;
-use async_channel;
 
 use reqwest as http;
 use http::header::HeaderValue;
@@ -25,13 +26,36 @@ use http::header::HeaderValue;
 use nix::{sys::time::{TimeVal, TimeValLike}};
 
 use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
 use chrono::{prelude::*, format::Fixed};
+use chrono_tz::Tz;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 
+mod archive;
+mod auth;
+mod checkpoint;
+mod consecutive;
+mod dedup;
+mod lock;
+mod meter;
+mod reuse;
+mod spill;
+mod staging;
+mod state;
+mod status;
+mod throttle;
+#[cfg(feature = "s3")]
+mod upload;
+use auth::OAuth;
+use checkpoint::{Backfill, Tracker};
 
 
 
-#[derive(Parser, Debug)]
+
+#[derive(Parser, Debug, Clone)]
 #[clap(about, version)]
+#[clap(group(clap::ArgGroup::new("layout")))]
 struct Opt
 {
 	/// Path to download folder
@@ -41,219 +65,4722 @@ struct Opt
 	/// Base URL of the webcam site
 	#[clap(default_value = "http://othcam.oth-regensburg.de/webcam/Regensburg/")]
 	url: http::Url,
+
+	/// Base URL of the images, if not served next to the listing, e.g. from a CDN
+	#[clap(long)]
+	image_base_url: Option<http::Url>,
+
+	/// Image URL with `{id}`, `{date}` and `{time}` placeholders instead of `<id><suffix>` below the base URL,
+	/// e.g. `https://cam.example/get?date={date}&time={time}&size=hu`
+	#[clap(long, conflicts_with = "image-base-url")]
+	url_from_template: Option<String>,
+
+	/// Layout: mirror the server directories encoded in image ids (default)
+	#[clap(long, group = "layout")]
+	preserve_server_paths: bool,
+
+	/// Layout: put all images directly into the download folder, replacing `/` in ids with `_`
+	#[clap(long, group = "layout")]
+	flatten_names: bool,
+
+	/// Layout: spread images over this many folders picked by a hash of the id, with names as for --flatten-names
+	#[clap(long, group = "layout")]
+	split_by_size: Option<u32>,
+
+	/// Put images into a folder named after the webcam, the last segment of the URL
+	#[clap(long)]
+	output_by_camera: bool,
+
+	/// Suffix appended to an image id to get the full-resolution image
+	#[clap(long, default_value = "_hu.jpg")]
+	suffix_full: String,
+
+	/// Suffix stripped from listed thumbnails to get the image id [default: detected from listing]
+	#[clap(long)]
+	suffix_thumb: Option<String>,
+
+	/// Extension replacing the one of the image suffixes, e.g. `png` for `_hu.png`
+	#[clap(long)]
+	image_extension: Option<String>,
+
+	/// Only look for images captured after the newest file time in the download folder
+	#[clap(long)]
+	since_file_mtime: bool,
+
+	/// Exit right away if the newest image of the listing is already in the download folder
+	#[clap(long, conflicts_with_all = &["from-stdin", "replace-corrupt"])]
+	since_newest_on_server: bool,
+
+	/// Skip images that exist in this folder with the same layout, e.g. an archive copied elsewhere (repeatable)
+	#[clap(long, parse(from_os_str), multiple_occurrences = true)]
+	seed_from_directory: Vec<PathBuf>,
+
+	/// Only download listed images whose ids (one per line) are in this file, and report the ones not listed
+	#[clap(long, parse(from_os_str), conflicts_with_all = &["from-stdin", "input-csv", "replace-corrupt"])]
+	image_id_allowlist_file: Option<PathBuf>,
+
+	/// Order of downloads; `oldest` enumerates the whole listing in memory before downloading
+	#[clap(long, arg_enum, default_value = "newest")]
+	order: Order,
+
+	/// Pick the number of workers by timing downloads of the newest images at rising concurrency first
+	#[clap(long)]
+	concurrency_auto: bool,
+
+	/// Stop searching after this many listing pages
+	#[clap(long)]
+	max_pages: Option<u32>,
+
+	/// Set file times from the capture time in the image id instead of Last-Modified;
+	/// existing files are then kept without asking the server
+	#[clap(long)]
+	stabilize_mtime: bool,
+
+	/// Source of file times; `min`/`max` take the earlier/later of Last-Modified and the capture time
+	/// [default: `id` with --stabilize-mtime, else `header`]
+	#[clap(long, arg_enum)]
+	mtime_source: Option<MtimeSource>,
+
+	/// Fail downloads without a valid Last-Modified header instead of using the capture or current time;
+	/// their ids are listed in the summary for review
+	#[clap(long)]
+	require_last_modified: bool,
+
+	/// Send this If-Modified-Since with every download instead of the file time, to see how the server
+	/// answers conditional requests, e.g. `2026-10-14 18:00` or RFC 3339
+	#[clap(long, parse(try_from_str = parse_datetime))]
+	pretend_modified_since: Option<DateTime<Utc>>,
+
+	/// Only take an image on the server as changed if its Last-Modified is more than this after the file time,
+	/// e.g. `30s`, for clocks that are a bit off
+	#[clap(long, parse(try_from_str = parse_interval))]
+	max_clock_skew: Option<time::Duration>,
+
+	/// Show only the position of the progress bar, for narrow terminals (the default below 60 columns)
+	#[clap(long)]
+	compact_progress: bool,
+
+	/// Point a symlink of this name in the download folder to the newest image downloaded by each run
+	#[clap(long, value_name = "NAME")]
+	symlink_latest: Option<String>,
+
+	/// Set the folder of each downloaded image to midnight of its capture date, for layouts with a folder per day
+	#[clap(long)]
+	preserve_directory_mtimes: bool,
+
+	/// Print the path of each downloaded file to stdout
+	#[clap(long)]
+	print_paths: bool,
+
+	/// Check the free space for the whole listing before downloading most of it
+	#[clap(long)]
+	check_space: bool,
+
+	/// Proceed without asking when a check fails
+	#[clap(long)]
+	yes: bool,
+
+	/// Walk the whole listing first, then show the image count and an estimated size and ask on the terminal
+	/// before downloading; without a terminal, only --yes continues
+	#[clap(long, conflicts_with_all = &["from-stdin", "input-csv", "replace-corrupt", "watch"])]
+	probe_then_confirm: bool,
+
+	/// Open a new connection for every request, for servers with broken keep-alive
+	#[clap(long)]
+	fresh_connection: bool,
+
+	/// Print when a connection is opened, pooled or reused, to check --fresh-connection or keep-alive
+	#[clap(long)]
+	debug_connections: bool,
+
+	/// Send TCP keepalive probes on idle connections this often, e.g. `30s`, for NATs that drop them [default: off]
+	#[clap(long, parse(try_from_str = parse_interval))]
+	tcp_keepalive: Option<time::Duration>,
+
+	/// Initial HTTP/2 flow control window per stream, in bytes [default: reqwest's]
+	#[clap(long)]
+	http2_initial_window: Option<u32>,
+
+	/// Initial HTTP/2 flow control window per connection, in bytes [default: reqwest's]
+	#[clap(long)]
+	http2_connection_window: Option<u32>,
+
+	/// Repeat requests that fail in HTTP/2 over HTTP/1.1, for servers with a broken HTTP/2
+	#[clap(long)]
+	http_version_fallback: bool,
+
+	/// Give each worker its own connection pool instead of sharing one
+	///
+	/// Only helps with servers that stall on busy connections; otherwise it just opens more of them.
+	#[clap(long)]
+	client_per_worker: bool,
+
+	/// Look up the hosts of the base URLs once and connect to those addresses, looking up again only
+	/// after a connection failure
+	#[clap(long, conflicts_with_all = &["client-per-worker", "http-version-fallback"])]
+	resolve_dns_once: bool,
+
+	/// Resume an interrupted listing walk, skipping images already done
+	#[clap(long)]
+	resume_partial_scan: bool,
+
+	/// Download strictly oldest first and checkpoint up to which image the archive has no gaps,
+	/// continuing from there on the next run; implies --order oldest, so the whole listing is walked
+	/// before the first download, instead of downloading while the walk goes on
+	#[clap(long, conflicts_with_all = &["resume-partial-scan", "priority"])]
+	download_order_checkpoint: bool,
+
+	/// Listing pages between checkpoint writes when resuming
+	#[clap(long, default_value_t = 5)]
+	checkpoint_pages: u32,
+
+	/// Reject downloads whose Content-Type is not one of --content-types
+	#[clap(long)]
+	format_check: bool,
+
+	/// Reject downloads without the JPEG start and end markers, e.g. error pages or cut off images
+	#[clap(long)]
+	magic_check: bool,
+
+	/// Reject downloads that don't fully decode as JPEG, which also catches corruption within the file
+	#[clap(long)]
+	decode_verify: bool,
+
+	/// Accepted content types for --format-check
+	#[clap(long, use_value_delimiter = true, default_value = "image/jpeg")]
+	content_types: Vec<String>,
+
+	/// Print a summary every few seconds instead of a line per image
+	#[clap(long)]
+	summary_only: bool,
+
+	/// Download priority; `recency` buffers the whole listing in memory to load the newest images first
+	#[clap(long, arg_enum, default_value = "none")]
+	priority: Priority,
+
+	/// Keep new downloads in a staging directory until the whole run succeeded
+	#[clap(long)]
+	atomic_archive: bool,
+
+	/// Staging directory for --atomic-archive [default: <DOWNLOAD_DIR>/.camscrub-staging]
+	#[clap(long, parse(from_os_str))]
+	staging_dir: Option<PathBuf>,
+
+	/// Stop starting new downloads after this long, e.g. `45m`
+	#[clap(long, parse(try_from_str = parse_deadline))]
+	deadline: Option<time::Instant>,
+
+	/// Fail a download when its body stalls for this long between chunks, e.g. `30s`;
+	/// this replaces the 10 s limit on the whole request, which still applies to connecting and the response headers
+	#[clap(long, parse(try_from_str = parse_interval))]
+	body_timeout: Option<time::Duration>,
+
+	/// Stop after this many downloaded images; unchanged ones don't count and in-flight ones still finish
+	#[clap(long)]
+	limit: Option<u64>,
+
+	/// Delay the start of each further worker by this much, e.g. `500ms`
+	#[clap(long, parse(try_from_str = humantime::parse_duration), default_value = "0s")]
+	ramp_delay: time::Duration,
+
+	/// Token endpoint for OAuth2 client credentials authentication
+	#[clap(long, requires_all = &["client-id", "client-secret"])]
+	oauth_token_url: Option<http::Url>,
+
+	/// OAuth2 client id
+	#[clap(long, requires = "oauth-token-url")]
+	client_id: Option<String>,
+
+	/// OAuth2 client secret
+	#[clap(long, requires = "oauth-token-url")]
+	client_secret: Option<String>,
+
+	/// Regex with named groups to extract fields from image ids; `date` and `time` give the capture time
+	#[clap(long, parse(try_from_str = parse_image_regex))]
+	image_name_regex: Option<Regex>,
+
+	/// Spill buffered listing pages to disk (with --order oldest or --check-space);
+	/// --priority recency still buffers in memory
+	#[clap(long)]
+	low_memory: bool,
+
+	/// Retries of failed listing requests
+	#[clap(long, default_value_t = 3)]
+	retries: u32,
+
+	/// Retries of image downloads that could not connect or timed out
+	#[clap(long, default_value_t = 3)]
+	connect_retries: u32,
+
+	/// Retries of image downloads whose body broke off
+	#[clap(long, default_value_t = 1)]
+	body_retries: u32,
+
+	/// Most retries in total over the whole run, including all passes of --watch
+	#[clap(long)]
+	retry_budget: Option<u32>,
+
+	/// Sync each image to disk before considering it saved
+	#[clap(long)]
+	fsync: bool,
+
+	/// Listing pages to fetch ahead while images are dispatched (0 fetches sequentially)
+	#[clap(long, default_value_t = 0)]
+	list_prefetch: usize,
+
+	/// Request the first listing page right away, while the archive is scanned for --seed-from-directory,
+	/// --since-file-mtime and the like; --list-prefetch does so anyway
+	#[clap(long)]
+	warm_cache: bool,
+
+	/// Drop the query of the URL from image download requests
+	#[clap(long)]
+	strip_query: bool,
+
+	/// Add a query parameter to image download requests (repeatable)
+	#[clap(long, value_name = "KEY=VALUE", multiple_occurrences = true, parse(try_from_str = parse_query_pair))]
+	append_query: Vec<(String, String)>,
+
+	/// Serve the stats of the running session as JSON on this port
+	#[clap(long)]
+	status_port: Option<u16>,
+
+	/// Address to bind the status port to
+	#[clap(long, default_value = "127.0.0.1")]
+	status_addr: std::net::IpAddr,
+
+	/// Keep only the first image of each interval of wall-clock time, e.g. `15m`
+	#[clap(long, parse(try_from_str = parse_interval))]
+	downsample: Option<time::Duration>,
+
+	/// Save all response headers of each downloaded image next to it as `<name>.headers.json`
+	#[clap(long)]
+	record_headers: bool,
+
+	/// Save capture time, URL, size, SHA-256, status and headers of each downloaded image next to it
+	/// as `<name>.meta.json`
+	#[clap(long)]
+	per_image_metadata_json: bool,
+
+	/// Also upload each downloaded image to this S3 bucket, with its source URL and capture time as metadata;
+	/// credentials and region come from the standard AWS variables like AWS_ACCESS_KEY_ID and AWS_REGION
+	#[cfg(feature = "s3")]
+	#[clap(long)]
+	s3_bucket: Option<String>,
+
+	/// Key prefix of the images uploaded to --s3-bucket, followed by their path in the download folder
+	#[cfg(feature = "s3")]
+	#[clap(long, requires = "s3-bucket")]
+	s3_prefix: Option<String>,
+
+	/// Endpoint of an S3-compatible store for --s3-bucket, e.g. `http://localhost:9000`, addressed by path
+	#[cfg(feature = "s3")]
+	#[clap(long, requires = "s3-bucket")]
+	s3_endpoint: Option<String>,
+
+	/// Repeat the scrape, waiting this long between passes, until interrupted with Ctrl-C
+	#[clap(long, parse(try_from_str = parse_interval), conflicts_with = "from-stdin")]
+	watch: Option<time::Duration>,
+
+	/// Exit --watch with code 3 once this many passes in a row got an empty listing, e.g. from an offline camera
+	#[clap(long, requires = "watch")]
+	exit_on_empty_listing: Option<u32>,
+
+	/// Exit with code 4 if no new image was downloaded, counting an empty listing as none, e.g. for a cron job to alert;
+	/// with --watch over all passes
+	#[clap(long)]
+	fail_on_zero_new: bool,
+
+	/// Stop --watch after the first pass ending past this time, e.g. `2026-10-14 18:00` or RFC 3339
+	#[clap(long, parse(try_from_str = parse_datetime), requires = "watch")]
+	poll_until: Option<DateTime<Utc>>,
+
+	/// Reuse listing pages fetched by an earlier pass of --watch for this long, e.g. `10m`;
+	/// the newest page is always fetched, and new images on it drop the others
+	#[clap(long, parse(try_from_str = parse_interval), requires = "watch")]
+	list_cache_ttl: Option<time::Duration>,
+
+	/// Save every listing response as fetched into this folder, named by page number and fetch time
+	#[clap(long, parse(from_os_str))]
+	list_response_save: Option<PathBuf>,
+
+	/// Save the redirects followed for each redirected image next to it as `<name>.redirects.json`
+	#[clap(long)]
+	capture_redirect_chain: bool,
+
+	/// Write the SHA-256 of all listed images to this file, checkable with `sha256sum -c`;
+	/// the checksums of earlier runs are kept for images still in the archive
+	#[clap(long, parse(from_os_str))]
+	checksum_manifest: Option<PathBuf>,
+
+	/// Hard-link new images to an identical one of an earlier run instead of keeping a copy;
+	/// needs file times that don't come from the server, as linked images share one
+	#[clap(long, conflicts_with = "atomic-archive")]
+	dedup_across_runs: bool,
+
+	/// Don't save images identical to the frame before them, listing them in `.camscrub-identical.tsv`;
+	/// implies --order oldest
+	#[clap(long, conflicts_with_all = &["atomic-archive", "dedup-across-runs", "priority", "throttle-on-error"])]
+	skip_identical_consecutive: bool,
+
+	/// Delete images of the download folder that are no longer listed, after a complete run; needs --yes
+	#[clap(long, conflicts_with_all = &["use-content-disposition", "from-stdin", "replace-corrupt"])]
+	delete_missing: bool,
+
+	/// What to do with the local copy of an image the server answers with 404, unlike --delete-missing
+	/// per download; `fail` aborts the run
+	#[clap(long, arg_enum, default_value = "keep")]
+	on_missing: OnMissing,
+
+	/// Keep the download folder in sync with the listing like rsync: implies --delete-missing,
+	/// and with the default file times from Last-Modified, changed images are downloaded again
+	#[clap(long, conflicts_with_all = &["use-content-disposition", "from-stdin", "replace-corrupt"])]
+	mirror_mode: bool,
+
+	/// Name downloaded images after the filename of a `Content-Disposition` response header, if any,
+	/// prefixed with the id to keep them apart; the names are kept in `.camscrub-dispositions.tsv`
+	#[clap(long)]
+	use_content_disposition: bool,
+
+	/// Remove partial images left by an interrupted run, then exit (also done before every run)
+	#[clap(long)]
+	purge_partials: bool,
+
+	/// Wait for another instance using the download folder to finish instead of exiting
+	#[clap(long)]
+	wait_for_lock: bool,
+
+	/// Summary at the end of a run; `json` prints it to stdout
+	#[clap(long, arg_enum, default_value = "text")]
+	summary_format: SummaryFormat,
+
+	/// Report downloads that took longer than this many milliseconds, with their size and throughput
+	#[clap(long, value_name = "MS")]
+	report_slow_images: Option<u64>,
+
+	/// Window of the recent throughput in the summary, next to the average over the whole run
+	#[clap(long, default_value = "30s", parse(try_from_str = parse_interval))]
+	bps_report_window: time::Duration,
+
+	/// Print throughput and ETA this often, e.g. `1m`, for logs without the progress bar
+	#[clap(long, parse(try_from_str = parse_interval))]
+	stats_interval: Option<time::Duration>,
+
+	/// Run fewer workers, with pauses, while more than --throttle-error-rate of recent downloads fail
+	#[clap(long)]
+	throttle_on_error: bool,
+
+	/// Share of failed recent downloads that makes --throttle-on-error slow down (0.0..1.0)
+	#[clap(long, default_value_t = 0.2, parse(try_from_str = parse_probability))]
+	throttle_error_rate: f64,
+
+	/// Abort the run on the first failed download
+	#[clap(long)]
+	fail_fast: bool,
+
+	/// Bundle the images downloaded in this run into a tarball in the download folder
+	#[clap(long, arg_enum)]
+	post_archive: Option<PostArchive>,
+
+	/// Remove the bundled images after --post-archive
+	#[clap(long, requires = "post-archive")]
+	remove_after_archive: bool,
+
+	/// Cut longer file names to this many bytes, adding a hash to keep them unique;
+	/// the original ids are listed in `.camscrub-names.tsv` in the download folder
+	#[clap(long)]
+	max_filename_length: Option<usize>,
+
+	/// Download images again that are in the archive but don't decode as JPEG, instead of the listing;
+	/// with --flatten-names, every `_` of a name is taken for a `/` of the id
+	#[clap(long, conflicts_with = "from-stdin")]
+	replace_corrupt: bool,
+
+	/// Read `{"id": "..."}` objects instead of plain lines with --from-stdin
+	#[clap(long, requires = "from-stdin")]
+	ndjson: bool,
+
+	/// Time zone of the capture times in image ids and of printed times, e.g. `Europe/Berlin`
+	/// [default: system time zone]
+	#[clap(long)]
+	timezone: Option<Tz>,
+
+	/// Read image ids (one per line) from stdin instead of the listing
+	#[clap(long)]
+	from_stdin: bool,
+
+	/// Download the images of a CSV file instead of the listing, with rows of `id[,url[,path]]`;
+	/// the URL and path (relative to the download folder) replace the ones derived from the id
+	#[clap(long, parse(from_os_str), conflicts_with_all = &["from-stdin", "replace-corrupt", "watch"])]
+	input_csv: Option<PathBuf>,
+
+	/// Fail downloads at random with this probability (0.0..1.0), for testing only
+	#[clap(long, default_value_t = 0.0, parse(try_from_str = parse_probability), hide_short_help = true)]
+	simulate_error_rate: f64,
+
+	/// Count the images in the listing, then exit
+	#[clap(long)]
+	count: bool,
+
+	/// Compare the local archive against the listing, then exit
+	#[clap(long)]
+	diff: bool,
+
+	/// List the differing images with --diff or --dry-run-diff
+	#[clap(long)]
+	diff_verbose: bool,
+
+	/// Ask the server which images a run would download or update, then exit without writing
+	#[clap(long)]
+	dry_run_diff: bool,
+
+	/// Check the listing and one image download, then exit
+	#[clap(long)]
+	probe: bool,
+
+	/// Check the images in the download folder (and the --checksum-manifest, if given) offline, then exit;
+	/// only JPEGs are decoded, other images are checked for being empty
+	#[clap(long)]
+	validate_only: bool,
+
+	/// Print the JSON Schema of the --summary-format json output or of the --status-port response, then exit
+	#[clap(long, arg_enum)]
+	print_schema: Option<Schema>,
+
+	/// Print version, git commit, build date, target and features of this build as JSON, then exit
+	#[clap(long)]
+	print_version_json: bool,
+
+	/// Check that the base URL and the listing endpoint respond, then exit
+	#[clap(long)]
+	test_url: bool,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Order {
+	Newest,
+	Oldest,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MtimeSource {
+	Header,
+	Id,
+	Min,
+	Max,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PostArchive {
+	#[clap(name = "tar.gz")]
+	TarGz,
+	#[clap(name = "tar.zst")]
+	TarZst,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Schema {
+	Report,
+	Status,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SummaryFormat {
+	Text,
+	Json,
+	Quiet,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnMissing {
+	Keep,
+	Delete,
+	Fail,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Priority {
+	None,
+	Recency,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>>
 {
-	let opt = Opt::parse();
-	if opt.url.cannot_be_a_base() {
+	let mut opt = Opt::parse();
+	if opt.url.cannot_be_a_base() || opt.image_base_url.as_ref().is_some_and(http::Url::cannot_be_a_base) {
 		return Err("URL is not supported".into());
 	}
+	if let Some(template) = &opt.url_from_template {
+		// the placeholders are replaced with digits, so leftover braces are unknown ones
+		match template_url(None, template, "2022/05/01/1200") {
+			Ok(url) if !url.as_str().contains(['{', '}']) && !url.as_str().contains("%7B") => (),
+			Ok(_) => return Err("--url-from-template has an unknown placeholder".into()),
+			Err(err) => return Err(format!("{:#}", err).into()),
+		}
+	}
+	if let Some(max) = opt.max_filename_length {
+		if max <= opt.suffix_full.len() + NAME_HASH_LEN + 1 {
+			return Err(format!("--max-filename-length must be more than {}", opt.suffix_full.len() + NAME_HASH_LEN + 1).into());
+		}
+	}
+	if opt.split_by_size == Some(0) {
+		return Err("--split-by-size must be at least 1".into());
+	}
+	if opt.max_pages == Some(0) {
+		return Err("--max-pages must be at least 1".into());
+	}
+	if opt.dedup_across_runs && !matches!(mtime_source(&opt), MtimeSource::Id | MtimeSource::Min) {
+		return Err("--dedup-across-runs needs --mtime-source id or min (or --stabilize-mtime)".into());
+	}
+	if opt.download_order_checkpoint || opt.skip_identical_consecutive {
+		opt.order = Order::Oldest;
+	}
+	if let Some(ext) = &opt.image_extension {
+		let ext = ext.trim_start_matches('.');
+		opt.suffix_full = replace_extension(&opt.suffix_full, ext);
+		opt.suffix_thumb = opt.suffix_thumb.as_deref().map(|suffix| replace_extension(suffix, ext));
+	}
+	if opt.replace_corrupt && !is_jpeg_suffix(&opt.suffix_full) {
+		return Err("--replace-corrupt can only check JPEG images (--suffix-full ending in .jpg or .jpeg)".into());
+	}
+
+	if opt.debug_connections {
+		reuse::Printer::install()?;
+	}
 
 	let rt = runtime::Builder::new_multi_thread()
 		.enable_all()
 		.build()?;
 
-	rt.block_on(run(opt))?;
+	let res = rt.block_on(run(opt));
 	rt.shutdown_timeout(time::Duration::from_secs(10));
-	Ok(())
+	match res {
+		Err(err) if err.is::<CameraDown>() => {
+			eprintln!("Error: {:#}", err);
+			std::process::exit(EXIT_CAMERA_DOWN);
+		},
+		Err(err) if err.is::<NothingNew>() => {
+			eprintln!("Error: {:#}", err);
+			std::process::exit(EXIT_NOTHING_NEW);
+		},
+		res => Ok(res?),
+	}
+}
+
+/// What --print-version-json reports, as embedded by build.rs
+#[derive(Serialize)]
+struct BuildInfo {
+	version: &'static str,
+	/// Not known when built outside a git checkout
+	git_commit: Option<&'static str>,
+	build_date: DateTime<Utc>,
+	target: &'static str,
+	features: Vec<&'static str>,
+}
+
+fn build_info() -> BuildInfo
+{
+	let non_empty = |s: &'static str| (!s.is_empty()).then_some(s);
+	BuildInfo {
+		version: env!("CARGO_PKG_VERSION"),
+		git_commit: non_empty(env!("CAMSCRUB_GIT_COMMIT")),
+		build_date: Utc.timestamp(env!("CAMSCRUB_BUILD_TIMESTAMP").parse().unwrap(), 0),
+		target: env!("CAMSCRUB_TARGET"),
+		features: env!("CAMSCRUB_FEATURES").split(',').filter_map(non_empty).collect(),
+	}
 }
 
 async fn run(opt: Opt) -> Result<()>
 {
+	if let Some(schema) = opt.print_schema {
+		let schema = match schema {
+			Schema::Report => schemars::schema_for!(Summary),
+			Schema::Status => schemars::schema_for!(status::Stats),
+		};
+		println!("{}", serde_json::to_string_pretty(&schema)?);
+		return Ok(());
+	}
+	if opt.print_version_json {
+		println!("{}", serde_json::to_string_pretty(&build_info())?);
+		return Ok(());
+	}
+	if opt.probe {
+		return probe(opt).await;
+	}
+	if opt.test_url {
+		return test_url(opt).await;
+	}
+	if opt.validate_only {
+		return validate(opt).await;
+	}
+	fs::create_dir_all(&opt.download_dir).await
+		.with_context(|| format!("failed to create directory {}", opt.download_dir.display()))?;
+	let _lock = lock::Lock::acquire(&opt.download_dir.join(LOCK_FILE), opt.wait_for_lock).await?;
+
+	if opt.purge_partials {
+		let count = purge_partials(&opt).await?;
+		println!("Removed {} partial images", count);
+		return Ok(());
+	}
+	if opt.count {
+		return count(opt).await;
+	}
+	if opt.diff {
+		return diff(opt).await;
+	}
+	if opt.dry_run_diff {
+		return dry_run_diff(opt).await;
+	}
+	let count = purge_partials(&opt).await?;
+	if count > 0 {
+		println!("Removed {} partial images of an interrupted run", count);
+	}
+	let opt = Arc::new(opt);
+
+	let stats = Arc::new(Mutex::new(status::Stats::default()));
+	if let Some(port) = opt.status_port {
+		status::serve((opt.status_addr, port).into(), stats.clone()).await?;
+	}
+
+	// once listened for, Ctrl-C no longer ends the process by itself, so a second one does
+	let shutdown = CancellationToken::new();
+	tokio::spawn({
+		let shutdown = shutdown.clone();
+		async move {
+			match tokio::signal::ctrl_c().await {
+				Ok(()) => shutdown.cancel(),
+				Err(err) => return eprintln!("failed to listen for Ctrl-C: {}", err),
+			}
+			if tokio::signal::ctrl_c().await.is_ok() {
+				std::process::exit(130);
+			}
+		}
+	});
+
+	// shared by all passes of --watch, so the retry budget holds for the whole run
+	let retries = Arc::new(Retries::new(&opt));
+	let interval = match opt.watch {
+		Some(interval) => interval,
+		None => return match scrape(opt.clone(), stats.clone(), None, retries, shutdown).await {
+			Err(err) if !(opt.fail_on_zero_new && err.is::<EmptyListing>()) => Err(err),
+			_ => check_new(&opt, &stats),
+		},
+	};
+	let list_cache = opt.list_cache_ttl.map(|ttl| Arc::new(ListCache::new(ttl)));
+	let mut passes = 0;
+	let mut empty = 0;
+	loop {
+		match scrape(opt.clone(), stats.clone(), list_cache.clone(), retries.clone(), shutdown.clone()).await {
+			Ok(()) => empty = 0,
+			Err(_) if shutdown.is_cancelled() => break,
+			// without a token, no later pass gets anywhere
+			Err(err) if err.is::<TokenRefresh>() => return Err(err),
+			Err(err) => {
+				eprintln!("pass failed: {:#}", err);
+				// other failures say nothing about the camera
+				if err.is::<EmptyListing>() {
+					empty += 1;
+					if opt.exit_on_empty_listing.is_some_and(|max| empty >= max) {
+						passes += 1;
+						break;
+					}
+				}
+			},
+		}
+		passes += 1;
+
+		let wait = match opt.poll_until {
+			Some(until) => match (until - Utc::now()).to_std() {
+				Ok(left) => interval.min(left),
+				Err(_) => break,
+			},
+			None => interval,
+		};
+		tokio::select! {
+			_ = time::sleep(wait) => (),
+			_ = shutdown.cancelled() => break,
+		}
+	}
+
+	if opt.summary_format == SummaryFormat::Text {
+		let stats = stats.lock().unwrap();
+		eprintln!("Total of {} passes: {} loaded, {} unchanged, {} failed",
+			passes, stats.downloaded, stats.exists, stats.failed);
+	}
+	if opt.exit_on_empty_listing.is_some_and(|max| empty >= max) {
+		return Err(CameraDown { passes: empty }.into());
+	}
+	check_new(&opt, &stats)
+}
+
+/// Fail with `NothingNew` for --fail-on-zero-new if no run downloaded anything
+fn check_new(opt: &Opt, stats: &Mutex<status::Stats>) -> Result<()>
+{
+	if opt.fail_on_zero_new && stats.lock().unwrap().downloaded == 0 {
+		return Err(NothingNew.into());
+	}
+	Ok(())
+}
+
+/// One pass over the listing
+async fn scrape(opt: Arc<Opt>, stats: Arc<Mutex<status::Stats>>, list_cache: Option<Arc<ListCache>>, retries: Arc<Retries>, shutdown: CancellationToken) -> Result<()>
+{
+	// the bar is drawn on stderr
+	let narrow = terminal_size::terminal_size_using_fd(std::os::unix::io::AsRawFd::as_raw_fd(&std::io::stderr()))
+		.is_some_and(|(width, _)| width.0 < COMPACT_WIDTH);
+	let template = match opt.compact_progress || narrow {
+		true => "{pos}/{len} {percent}%",
+		false => "{msg} {pos:>6}/{len:6} {elapsed_precise}",
+	};
 	let pb = ProgressBar::new(0)
 		.with_style(indicatif::ProgressStyle::default_bar()
-			.template("{msg} {pos:>6}/{len:6} {elapsed_precise}")
+			.template(template)
 			.progress_chars("##-"));
 	pb.set_draw_rate(4);
 	let pb = Arc::new(pb);
 
-	let url_base = {
-		let mut url = opt.url.clone();
-		url.path_segments_mut().unwrap().pop_if_empty();
-		url
-	};
+	let url_base = base_url(&opt.url);
+
+	let client = Client { list_cache: list_cache.clone(), retries, ..build_client(&opt).await? };
 
-	let client = http::Client::builder()
-		.timeout(time::Duration::from_secs(10))
-		.build()
-		.context("failed to build http client")?;
+	if opt.since_newest_on_server {
+		if let Some(img) = newest_if_archived(&opt, &client, &url_base, &pb).await? {
+			println!("Archive up to date (newest: {})", img);
+			return Ok(());
+		}
+	}
 
-	let task_range = 0..4;
-	let (img_tx, img_rx) = async_channel::bounded::<String>(64 * task_range.len());
+	let workers = match opt.concurrency_auto {
+		true => calibrate(&opt, &client, &url_base, &pb).await?,
+		false => WORKERS,
+	};
+	let task_range = 0..workers;
+	let (img_tx, img_rx) = async_channel::bounded::<Msg>(64 * task_range.len());
+	let img_rx = match opt.priority {
+		Priority::None => img_rx,
+		Priority::Recency => {
+			let (work_tx, work_rx) = async_channel::bounded::<Msg>(1);
+			tokio::spawn(prioritize(img_rx, work_tx, opt.image_name_regex.clone(), opt.timezone));
+			work_rx
+		},
+	};
+	let (res_tx, res_rx) = async_channel::unbounded::<Outcome>();
 	let mut tasks = Vec::new();
+	let dirs = Arc::new(DirCache::default());
+	let samples = Arc::new(SizeSamples::default());
+	// ends the run right away, on Ctrl-C or a failure that aborts the run; workers drop their downloads
+	let cancel = shutdown.child_token();
+	// stops handing out images once --limit or the deadline is reached, in-flight downloads still finish
+	let stop = cancel.child_token();
+	if let Some(deadline) = opt.deadline {
+		let stop = stop.clone();
+		tokio::spawn(async move {
+			tokio::select! {
+				_ = time::sleep_until(deadline) => stop.cancel(),
+				_ = stop.cancelled() => (),
+			}
+		});
+	}
+	let downloaded = Arc::new(AtomicU64::new(0));
+	// first failed token refresh of a worker, which aborts the run
+	let refresh_failed = Arc::new(Mutex::new(None::<TokenRefresh>));
+	let throttle = opt.throttle_on_error
+		.then(|| Arc::new(throttle::Throttle::new(task_range.len(), opt.throttle_error_rate)));
+	let stats_task = opt.stats_interval
+		.map(|interval| tokio::spawn(print_throughput(interval, pb.clone(), samples.clone())));
+
+	let tracker = if opt.resume_partial_scan {
+		fs::create_dir_all(&opt.download_dir).await
+			.with_context(|| format!("failed to create directory {}", opt.download_dir.display()))?;
+		Some(Arc::new(Tracker::load(&opt.download_dir.join(CHECKPOINT_FILE)).await?))
+	} else {
+		None
+	};
+	let backfill = match opt.download_order_checkpoint {
+		true => Some(Arc::new(Backfill::load(&opt.download_dir.join(BACKFILL_FILE)).await?)),
+		false => None,
+	};
+	let index = match opt.dedup_across_runs {
+		true => Some(Arc::new(dedup::Index::load(&opt.download_dir.join(HASHES_FILE), &opt.download_dir).await?)),
+		false => None,
+	};
+	// left by a run that was killed, which must not be committed with this one
+	if let Some(staging) = staging_dir(&opt) {
+		if fs::metadata(&staging).await.is_ok() {
+			tokio::task::spawn_blocking({
+				let staging = staging.clone();
+				move || staging::discard(&staging)
+			}).await??;
+			pb.println(format!("Removed staging directory {} of an interrupted run", staging.display()));
+		}
+	}
 
+	let task_range_len = task_range.len();
 	for id in task_range.clone() {
 		let img_rx = img_rx.clone();
 		let pb = pb.clone();
-		let client = client.clone();
-		let url_base = url_base.clone();
-		let download_dir = opt.download_dir.clone();
+		let client = match opt.client_per_worker {
+			true => Client { http: http_client(&opt, client.redirects.as_ref())?, ..client.clone() },
+			false => client.clone(),
+		};
+		let img_base = image_base(&opt);
+		let opt = opt.clone();
+		let dirs = dirs.clone();
+		let res_tx = res_tx.clone();
+		let samples = samples.clone();
+		let cancel = cancel.clone();
+		let stop = stop.clone();
+		let downloaded = downloaded.clone();
+		let refresh_failed = refresh_failed.clone();
+		let throttle = throttle.clone();
 
 		let task = tokio::spawn(async move {
-			while let Ok(img) = img_rx.recv().await {
-				if img.is_empty() {
-					break;
+			time::sleep(opt.ramp_delay * id as u32).await;
+			loop {
+				let msg = tokio::select! {
+					msg = img_rx.recv() => match msg {
+						Ok(msg) => msg,
+						Err(_) => break,
+					},
+					_ = cancel.cancelled() => break,
+				};
+				let (img, url, path) = match msg {
+					Msg::Image(img) if img.is_empty() => {
+						pb.println("skipping empty image id");
+						if let Some(frames) = &client.frames {
+							frames.finish(&img, consecutive::Content::Unknown);
+						}
+						continue;
+					},
+					Msg::Image(img) => (img, None, None),
+					Msg::Row(Row { img, url, path }) => (img, url, path),
+				};
+				// drain the queue, so the feed isn't stuck on a full channel
+				if stop.is_cancelled() {
+					if let Some(frames) = &client.frames {
+						frames.finish(&img, consecutive::Content::Unknown);
+					}
+					continue;
 				}
 
-				let img_path = img.clone() + "_hu.jpg";
-				let url = {
-					let mut url = url_base.clone();
-					url.path_segments_mut().unwrap().extend(img_path.split('/'));
-					url
-				};
-				let mut path = download_dir.clone();
-				path.push(&img_path);
+				let img_path = img.clone() + &opt.suffix_full;
+				let url = url.map_or_else(|| image_location(&opt, &img_base, &img), Ok);
+				let path = path.unwrap_or_else(|| image_file(&opt, &img_path));
 
-				match download(&client, &url, &path).await {
+				let slot = match &throttle {
+					Some(throttle) => tokio::select! {
+						slot = throttle.acquire() => Some(slot),
+						_ = cancel.cancelled() => break,
+					},
+					None => None,
+				};
+				let started = time::Instant::now();
+				let result = async {
+					download_retrying(&opt, &client, &pb, &img, &url?, &path, &dirs).await
+				};
+				let result = async {
+					match opt.deadline {
+						Some(deadline) => time::timeout_at(deadline + DEADLINE_GRACE, result).await
+							.unwrap_or_else(|_| Err(anyhow::anyhow!("aborted at the deadline"))),
+						None => result.await,
+					}
+				};
+				let result = tokio::select! {
+					result = result => result,
+					_ = cancel.cancelled() => break,
+				};
+				let elapsed = started.elapsed();
+				drop(slot);
+				if let Some(frames) = &client.frames {
+					frames.finish(&img, match &result {
+						Ok(Download { sha256: Some(sha256), .. }) => consecutive::Content::Hash(sha256.clone()),
+						Ok(Download { status: Status::Exists, path, .. }) => consecutive::Content::File(path.clone()),
+						_ => consecutive::Content::Unknown,
+					});
+				}
+				if let (Some(ms), Ok(v)) = (opt.report_slow_images, &result) {
+					if elapsed.as_millis() > ms as u128 {
+						pb.println(format!("slow download {}: {} ms for {} KiB ({:.1} KiB/s)",
+							img, elapsed.as_millis(), v.size >> 10, v.size as f64 / 1024.0 / elapsed.as_secs_f64()));
+					}
+				}
+				match &result {
 					Ok(v) => {
 						pb.inc(1);
-						let stat = match v {
-							Status::Downloaded => pb.println(format!("loaded {} ...", img)),
-							Status::Exists => (),
+						match v.status {
+							Status::Downloaded => {
+								let count = downloaded.fetch_add(1, Ordering::Relaxed) + 1;
+								if opt.limit.is_some_and(|limit| count >= limit) {
+									stop.cancel();
+								}
+								if !opt.summary_only {
+									pb.println(format!("loaded {} ...", img));
+								}
+								if opt.print_paths {
+									println!("{}", v.path.display());
+								}
+							},
+							Status::Identical if !opt.summary_only => pb.println(format!("skipped {}, identical to the frame before", img)),
+							Status::Identical | Status::Exists => (),
 						};
 					},
-					Err(err) => pb.println(format!("failed to download {}: {}", &img_path, err)),
+					Err(err) if !opt.summary_only => pb.println(format!("failed to download {}: {}", &img_path, err)),
+					Err(_) => (),
+				}
+				if result.as_ref().err().is_some_and(is_not_found) {
+					match opt.on_missing {
+						OnMissing::Keep => (),
+						OnMissing::Delete => match remove_image(&path).await {
+							Ok(true) => pb.println(format!("deleted {}, which is gone from the server", path.display())),
+							Ok(false) => (),
+							Err(err) => pb.println(format!("{:#}", err)),
+						},
+						OnMissing::Fail => cancel.cancel(),
+					}
+				}
+				let path = match &result {
+					Ok(v) => v.path.clone(),
+					Err(_) => path,
+				};
+				samples.add(&result);
+				let failed = result.is_err();
+				if let Some(limit) = throttle.as_ref().and_then(|throttle| throttle.record(failed)) {
+					pb.println(format!("{} of {} workers active", limit, task_range_len));
+				}
+				// later requests would fail the same way
+				if let Some(err) = result.as_ref().err().and_then(|err| err.downcast_ref::<TokenRefresh>()) {
+					refresh_failed.lock().unwrap().get_or_insert_with(|| err.clone());
+					cancel.cancel();
+				}
+				res_tx.send(Outcome { img, path, result, elapsed }).await.ok();
+				if failed && opt.fail_fast {
+					cancel.cancel();
 				}
 			}
 		});
 		tasks.push(task);
 	}
+	drop(res_tx);
 
-	#[derive(Serialize)]
-	struct ListRequest {
-		wc: String,
-		thumbs: u32,
-	}
+	let collector = {
+		let opt = opt.clone();
+		let tracker = tracker.clone();
+		let backfill = backfill.clone();
+		let index = index.clone();
+		let stats = stats.clone();
+		let pb = pb.clone();
+		let summary_only = opt.summary_only;
+		tokio::spawn(async move {
+			let mut report = Report::default();
+			let mut window = Report::default();
+			let mut interval = time::interval(SUMMARY_INTERVAL);
+			interval.tick().await;
 
-	#[derive(Deserialize)]
-	struct ListResponse {
-		thumbs: Vec<String>,
-	}
+			loop {
+				let outcome = tokio::select! {
+					outcome = res_rx.recv() => match outcome {
+						Ok(outcome) => outcome,
+						Err(_) => break,
+					},
+					_ = interval.tick(), if summary_only => {
+						window.print_window(&pb);
+						window = Report::default();
+						continue;
+					},
+				};
 
-	let webcam = url_base.path_segments()
-		.and_then(Iterator::last)
-		.context("missing webcam at the end of URL")?;
+				if let (Some(tracker), Ok(_)) = (&tracker, &outcome.result) {
+					tracker.finish(&outcome.img);
+				}
+				if let (Some(ms), Ok(download)) = (opt.report_slow_images, &outcome.result) {
+					if outcome.elapsed.as_millis() > ms as u128 {
+						report.slow.push(SlowImage {
+							img: outcome.img.clone(),
+							millis: outcome.elapsed.as_millis() as u64,
+							size: download.size,
+						});
+					}
+				}
+				if let (Some(backfill), Ok(_)) = (&backfill, &outcome.result) {
+					backfill.finish(&outcome.img);
+				}
+				if let (Some(index), Ok(Download { status: Status::Downloaded, sha256: Some(sha256), .. })) = (&index, &outcome.result) {
+					match index.link(sha256, &outcome.path).await {
+						Ok(linked) => report.linked += linked as u64,
+						Err(err) => pb.println(format!("failed to deduplicate {}: {:#}", outcome.img, err)),
+					}
+				}
+				if let (true, Ok(Download { status: Status::Downloaded, .. })) = (opt.preserve_directory_mtimes, &outcome.result) {
+					let dir = outcome.path.parent().unwrap_or(&outcome.path);
+					let midnight = image_time(opt.image_name_regex.as_ref(), opt.timezone, &outcome.img)
+						.and_then(|time| from_wall_clock(opt.timezone, wall_clock(opt.timezone, time).date().and_hms(0, 0, 0)));
+					// the image root holds images of all days
+					if let (Some(midnight), false) = (midnight, dir == image_root(&opt)) {
+						let earliest = report.day_dirs.entry(dir.to_owned()).or_insert(midnight);
+						*earliest = midnight.min(*earliest);
+					}
+				}
+				if matches!(outcome.result, Ok(Download { status: Status::Downloaded | Status::Exists, .. })) && is_shortened(&opt, &outcome.img, &outcome.path) {
+					report.renamed.insert(outcome.path.clone(), outcome.img.clone());
+				}
+				if let (Some(_), Ok(Download { status: Status::Downloaded, .. })) = (&opt.symlink_latest, &outcome.result) {
+					let key = Recent(image_time(opt.image_name_regex.as_ref(), opt.timezone, &outcome.img), outcome.img.clone());
+					if report.latest.as_ref().is_none_or(|(latest, _)| key > *latest) {
+						report.latest = Some((key, outcome.path.clone()));
+					}
+				}
+				let finished = report.finished();
+				report.add(&outcome);
+				window.add(&outcome);
+				stats.lock().unwrap().add(&outcome);
+				// failures don't move the count, so each multiple saves once
+				if let (Some(backfill), true) = (&backfill, report.finished() / BACKFILL_SAVE_IMAGES > finished / BACKFILL_SAVE_IMAGES) {
+					if let Err(err) = backfill.save().await {
+						pb.println(format!("{:#}", err));
+					}
+				}
+			}
+			if summary_only {
+				window.print_window(&pb);
+			}
+			report.untimed.sort();
+			report
+		})
+	};
 
-	let url_list = {
-		let mut url = url_base.clone();
-		url.path_segments_mut().unwrap()
-			.pop()
-			.extend(["include", "list.php"]);
-		url
+	let feed = async {
+		if opt.from_stdin {
+			feed_stdin(&opt, &pb, &img_tx).await
+		} else if let Some(csv) = &opt.input_csv {
+			feed_csv(&opt, csv, &pb, &img_tx).await
+		} else if opt.replace_corrupt {
+			feed_corrupt(&opt, &pb, &img_tx).await
+		} else {
+			feed_listing(&opt, &client, &pb, &img_tx, &samples, tracker.as_deref(), backfill.as_deref()).await
+		}
+	};
+	let fed = tokio::select! {
+		// cancelling also stops, which must not pass for a complete scan
+		biased;
+		_ = cancel.cancelled() => Err(anyhow::anyhow!(match shutdown.is_cancelled() {
+			true => "interrupted",
+			false => "cancelled after a failed download",
+		})),
+		_ = stop.cancelled() => Ok(Scan { truncated: true, listed: None }),
+		fed = feed => fed,
 	};
-	let list_req = ListRequest { wc: webcam.to_owned(), thumbs: 500 };
+	if let Err(err) = &fed {
+		pb.println(format!("stopped searching: {:#}", err));
+	}
 
-	let mut img_oldest = String::new();
-	let req_base = client
-		.get(url_list)
-		.query(&list_req);
+	// Terminate tasks: once the queue is empty, receiving fails..
+	drop(img_tx);
+	// ..and await their end
+	for task in tasks {
+		task.await.ok();
+	}
+	if let Some(task) = stats_task {
+		task.abort();
+	}
+	let mut report = collector.await.context("failed to collect results")?;
+	if let Some(tracker) = &tracker {
+		tracker.save().await?;
+	}
+	if let Some(backfill) = &backfill {
+		backfill.save().await?;
+	}
+	if let Some(index) = &index {
+		index.save().await?;
+	}
+	report.truncated = matches!(fed, Ok(Scan { truncated: true, .. }));
+	report.deadline = deadline_passed(&opt);
+	report.limited = opt.limit.is_some_and(|limit| downloaded.load(Ordering::Relaxed) >= limit);
+	report.throughput = Throughput {
+		average: client.meter.average(),
+		recent: client.meter.recent(),
+		window: client.meter.window().as_secs(),
+	};
+	if !report.renamed.is_empty() {
+		record_names(&opt, &report.renamed).await?;
+	}
 
-	pb.println(format!("Searching image URLs in {} ...", &url_base));
-	loop {
-		let res = req_base.try_clone().unwrap()
-			.query(&[("img", &img_oldest)])
-			.send()
-			.await.context("failed to send request")?
-			.json::<ListResponse>()
-			.await.context("failed to parse response")?;
+	if let Some(staging) = staging_dir(&opt) {
+		let target = opt.download_dir.clone();
+		report.staged = Some(if fed.is_ok() && report.failed == 0 {
+			let count = tokio::task::spawn_blocking(move || staging::commit(&staging, &target))
+				.await??;
+			Staged::Committed(count)
+		} else {
+			tokio::task::spawn_blocking(move || staging::discard(&staging))
+				.await??;
+			Staged::Discarded
+		});
+	}
 
-		let img_urls: Vec<_> = res.thumbs.into_iter()
-			.map(|img| img.strip_suffix("_la.jpg")
-				.map(|s| s.to_owned()).unwrap_or(img))
-			.collect();
+	if let Some(manifest) = &opt.checksum_manifest {
+		// discarded downloads are gone, and any files they would have replaced keep their earlier checksums
+		if matches!(report.staged, Some(Staged::Discarded)) {
+			for path in &report.new_paths {
+				report.checksums.remove(path);
+			}
+		}
+		write_manifest(manifest, &report.checksums).await?;
+	}
+
+	if !matches!(report.staged, Some(Staged::Discarded)) {
+		for (dir, midnight) in &report.day_dirs {
+			let tv = TimeVal::milliseconds(midnight.timestamp_millis());
+			if let Err(err) = nix::sys::stat::utimes(dir, &tv, &tv) {
+				pb.println(format!("failed to set the time of {}: {}", dir.display(), err));
+			}
+		}
+	}
+
+	if opt.delete_missing || opt.mirror_mode {
+		// a failed download might have been removed on purpose, or not
+		match (&fed, report.failed) {
+			(Ok(Scan { listed: Some(listed), .. }), 0) => delete_missing(&opt, &pb, listed).await?,
+			_ => pb.println("not deleting unlisted images, the listing was not walked completely without errors"),
+		}
+	}
+
+	if let (Some(name), Some((_, latest))) = (&opt.symlink_latest, &report.latest) {
+		if !matches!(report.staged, Some(Staged::Discarded)) {
+			link_latest(&opt, name, latest).await?;
+		}
+	}
+
+	if let Some(format) = opt.post_archive {
+		post_archive(&opt, &pb, format, &report.new_paths).await?;
+	}
+
+	// a failed download may be a removed image, so the cached listing can't be trusted anymore
+	if let (Some(list_cache), true) = (&list_cache, report.failed > 0) {
+		list_cache.clear();
+	}
+
+	report.complete = matches!(fed, Ok(Scan { truncated: false, .. }))
+		&& report.failed == 0 && !report.deadline && !report.limited;
+	report.finish(opt.summary_format, &pb);
+
+	if shutdown.is_cancelled() {
+		anyhow::bail!("interrupted by Ctrl-C");
+	}
+	if let Some(err) = refresh_failed.lock().unwrap().take() {
+		return Err(err.into());
+	}
+	if cancel.is_cancelled() {
+		match opt.fail_fast {
+			true => anyhow::bail!("aborted on the first failed download (--fail-fast)"),
+			false => anyhow::bail!("aborted on an image gone from the server (--on-missing fail)"),
+		}
+	}
+	fed.map(drop)
+}
+
+/// Work item for the download workers
+enum Msg {
+	Image(String),
+	/// Image of --input-csv
+	Row(Row),
+}
+
+/// Image with the URL and path it is downloaded from and to, unless derived from its id
+struct Row {
+	img: String,
+	url: Option<http::Url>,
+	path: Option<PathBuf>,
+}
+
+/// Result of a worker processing one image
+struct Outcome {
+	img: String,
+	path: PathBuf,
+	result: Result<Download>,
+	elapsed: time::Duration,
+}
+
+/// Aggregated outcomes of a run
+#[derive(Default)]
+struct Report {
+	downloaded: u64,
+	exists: u64,
+	failed: u64,
+	responses: u64,
+	not_modified: u64,
+	cache_control: u64,
+	expires: u64,
+	last_modified_age: i64,
+	last_modified: u64,
+	truncated: bool,
+	deadline: bool,
+	limited: bool,
+	/// Images downloaded in this run, for --post-archive
+	new_paths: Vec<PathBuf>,
+	/// Ids of images whose names were cut by --max-filename-length
+	renamed: BTreeMap<PathBuf, String>,
+	/// SHA-256 of each image by path, for --checksum-manifest
+	checksums: BTreeMap<PathBuf, String>,
+	/// Ids of images failed by --require-last-modified
+	untimed: Vec<String>,
+	/// New images hard-linked to an identical earlier one by --dedup-across-runs
+	linked: u64,
+	/// Images not saved as identical to the frame before by --skip-identical-consecutive
+	identical: u64,
+	/// Images slower than --report-slow-images
+	slow: Vec<SlowImage>,
+	/// Earliest midnight of the images downloaded into each folder, for --preserve-directory-mtimes
+	day_dirs: BTreeMap<PathBuf, DateTime<Utc>>,
+	/// Newest image downloaded in this run by capture time and id, for --symlink-latest
+	latest: Option<(Recent, PathBuf)>,
+	throughput: Throughput,
+	staged: Option<Staged>,
+	/// All listed images were processed without errors
+	complete: bool,
+}
+
+impl Report
+{
+	/// Images handled without an error
+	fn finished(&self) -> u64
+	{
+		self.downloaded + self.exists + self.identical
+	}
+
+	fn add(&mut self, outcome: &Outcome)
+	{
+		let download = match &outcome.result {
+			Ok(download) => download,
+			Err(err) => {
+				if err.is::<Untimed>() {
+					self.untimed.push(outcome.img.clone());
+				}
+				self.failed += 1;
+				return;
+			},
+		};
+
+		match download.status {
+			Status::Downloaded => {
+				self.downloaded += 1;
+				self.new_paths.push(outcome.path.clone());
+			},
+			Status::Exists => self.exists += 1,
+			Status::Identical => self.identical += 1,
+		}
+		// a skipped frame has no file to check
+		if let (Some(sha256), false) = (&download.sha256, matches!(download.status, Status::Identical)) {
+			self.checksums.insert(outcome.path.clone(), sha256.clone());
+		}
+
+		let headers = match &download.headers {
+			Some(headers) => headers,
+			None => return,
+		};
+		self.responses += 1;
+		self.not_modified += matches!(download.status, Status::Exists) as u64;
+		self.cache_control += headers.cache_control as u64;
+		self.expires += headers.expires as u64;
+		if let Some(mtime) = headers.last_modified {
+			self.last_modified_age += (headers.date - mtime).num_seconds();
+			self.last_modified += 1;
+		}
+	}
+
+	/// Print a one-line summary of the images since the last window
+	fn print_window(&self, pb: &ProgressBar)
+	{
+		if self.downloaded + self.exists + self.failed > 0 {
+			pb.println(format!("{} loaded, {} unchanged, {} failed", self.downloaded, self.exists, self.failed));
+		}
+	}
+
+	/// Print the summary at the end of a run and finish the progress bar
+	fn finish(&self, format: SummaryFormat, pb: &ProgressBar)
+	{
+		match format {
+			SummaryFormat::Text => {
+				self.print(pb);
+				pb.finish_with_message(if self.complete {
+					"Download complete!"
+				} else {
+					"Download partially complete (some errors occurred)!"
+				});
+			},
+			SummaryFormat::Json => {
+				pb.finish_and_clear();
+				println!("{}", serde_json::to_string(&self.summary()).unwrap());
+			},
+			SummaryFormat::Quiet => pb.finish_and_clear(),
+		}
+	}
+
+	fn print(&self, pb: &ProgressBar)
+	{
+		if self.truncated {
+			pb.println("Search was truncated before the end of the listing");
+		}
+		if self.deadline {
+			pb.println("Deadline reached, stopped early");
+		}
+		if self.limited {
+			pb.println("Download limit reached, stopped early");
+		}
+		pb.println(format!("Images: {} loaded, {} unchanged, {} failed",
+			self.downloaded, self.exists, self.failed));
+		pb.println(format!("Responses: {}, not modified (304): {}, with Cache-Control: {}, with Expires: {}",
+			self.responses, self.not_modified, self.cache_control, self.expires));
+		if self.downloaded > 0 {
+			let Throughput { average, recent, window } = self.throughput;
+			pb.println(format!("Throughput: {:.1} KiB/s on average, {:.1} KiB/s over the last {}s",
+				average / 1024.0, recent / 1024.0, window));
+		}
+		if let Some(age) = self.average_age() {
+			pb.println(format!("Average Last-Modified age: {}d {}h {}m",
+				age.num_days(), age.num_hours() % 24, age.num_minutes() % 60));
+		}
+		if !self.slow.is_empty() {
+			pb.println(format!("Slow downloads: {}", self.slow.len()));
+		}
+		if self.linked > 0 {
+			pb.println(format!("Hard-linked {} duplicates of earlier images", self.linked));
+		}
+		if self.identical > 0 {
+			pb.println(format!("Skipped {} images identical to the frame before", self.identical));
+		}
+		if !self.untimed.is_empty() {
+			pb.println(format!("Failed without a valid Last-Modified: {}", self.untimed.len()));
+			for img in &self.untimed {
+				pb.println(format!("  {}", img));
+			}
+		}
+		match self.staged {
+			Some(Staged::Committed(count)) => pb.println(format!("Moved {} staged images into the archive", count)),
+			Some(Staged::Discarded) => pb.println("Discarded staged images as the run did not succeed"),
+			None => (),
+		}
+	}
+
+	fn summary(&self) -> Summary
+	{
+		Summary {
+			complete: self.complete,
+			truncated: self.truncated,
+			deadline: self.deadline,
+			limited: self.limited,
+			images: ImageCounts {
+				downloaded: self.downloaded,
+				exists: self.exists,
+				failed: self.failed,
+				untimed: self.untimed.clone(),
+				linked: self.linked,
+				identical: self.identical,
+				slow: self.slow.clone(),
+			},
+			responses: ResponseCounts {
+				total: self.responses,
+				not_modified: self.not_modified,
+				cache_control: self.cache_control,
+				expires: self.expires,
+				average_last_modified_age: self.average_age().map(|age| age.num_seconds()),
+			},
+			throughput: self.throughput,
+			staged: self.staged,
+		}
+	}
+
+	fn average_age(&self) -> Option<chrono::Duration>
+	{
+		(self.last_modified > 0)
+			.then(|| chrono::Duration::seconds(self.last_modified_age / self.last_modified as i64))
+	}
+}
+
+/// Summary of a run as printed by --summary-format json
+#[derive(Serialize, JsonSchema)]
+struct Summary {
+	/// All listed images were processed without errors
+	complete: bool,
+	/// The search stopped before the end of the listing
+	truncated: bool,
+	/// The --deadline was reached
+	deadline: bool,
+	/// The --limit was reached
+	limited: bool,
+	images: ImageCounts,
+	responses: ResponseCounts,
+	throughput: Throughput,
+	/// Outcome of --atomic-archive, if used
+	staged: Option<Staged>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ImageCounts {
+	downloaded: u64,
+	exists: u64,
+	failed: u64,
+	/// Ids of the failed images without a valid Last-Modified (--require-last-modified)
+	untimed: Vec<String>,
+	/// Downloaded images hard-linked to an identical earlier one (--dedup-across-runs)
+	linked: u64,
+	/// Images not saved as identical to the frame before (--skip-identical-consecutive)
+	identical: u64,
+	/// Images slower than --report-slow-images
+	slow: Vec<SlowImage>,
+}
+
+/// Image that took long to download
+#[derive(Serialize, JsonSchema, Clone)]
+struct SlowImage {
+	img: String,
+	millis: u64,
+	/// Bytes downloaded, 0 if unchanged
+	size: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ResponseCounts {
+	total: u64,
+	not_modified: u64,
+	cache_control: u64,
+	expires: u64,
+	/// Average age of the Last-Modified headers in seconds
+	average_last_modified_age: Option<i64>,
+}
+
+/// Download rate of image bodies in bytes per second
+#[derive(Serialize, JsonSchema, Default, Clone, Copy)]
+struct Throughput {
+	/// Over the whole run
+	average: f64,
+	/// Over the last --bps-report-window
+	recent: f64,
+	/// Length of that window in seconds
+	window: u64,
+}
+
+/// What happened to the staging directory of --atomic-archive
+#[derive(Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Staged {
+	Committed(u64),
+	Discarded,
+}
+
+/// Terminals narrower than this get the --compact-progress bar
+const COMPACT_WIDTH: u16 = 60;
+
+const SUMMARY_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Download workers unless --concurrency-auto picks a count
+const WORKERS: usize = 4;
+
+const LOCK_FILE: &str = ".camscrub.lock";
+
+const CHECKPOINT_FILE: &str = ".camscrub-checkpoint.json";
+
+const HASHES_FILE: &str = ".camscrub-hashes.tsv";
+
+const BACKFILL_FILE: &str = ".camscrub-backfill.json";
+
+const IDENTICAL_FILE: &str = ".camscrub-identical.tsv";
+
+/// Finished images between backfill checkpoint writes
+const BACKFILL_SAVE_IMAGES: u64 = 100;
+
+/// Pages of image ids from the listing, optionally fetched ahead by a background task
+enum PageFeed {
+	Direct {
+		list: Box<Listing>,
+		img_oldest: String,
+		suffix_thumb: Option<String>,
+		pb: ProgressBar,
+	},
+	Prefetch(async_channel::Receiver<Result<Vec<String>>>),
+	/// Fetching the first page in the background, for --warm-cache
+	Warming(tokio::task::JoinHandle<(PageFeed, Result<Vec<String>>)>),
+}
+
+impl PageFeed
+{
+	fn new(opt: &Opt, list: Listing, img_oldest: &str, pb: &ProgressBar) -> Self
+	{
+		let feed = PageFeed::Direct {
+			list: Box::new(list),
+			img_oldest: img_oldest.to_owned(),
+			suffix_thumb: opt.suffix_thumb.clone(),
+			pb: pb.clone(),
+		};
+		if opt.list_prefetch == 0 && opt.warm_cache {
+			return PageFeed::Warming(tokio::spawn(async move {
+				let mut feed = feed;
+				let page = feed.next().await;
+				(feed, page)
+			}));
+		}
+		if opt.list_prefetch == 0 {
+			return feed;
+		}
+
+		// the cursor of the next page is known right after parsing, so fetch on while pages get dispatched
+		let (tx, rx) = async_channel::bounded(opt.list_prefetch);
+		tokio::spawn(async move {
+			let mut feed = feed;
+			loop {
+				let page = feed.next().await;
+				let last = !matches!(&page, Ok(page) if !page.is_empty());
+				if tx.send(page).await.is_err() || last {
+					break;
+				}
+			}
+		});
+		PageFeed::Prefetch(rx)
+	}
+
+	/// Next page of image ids, empty at the end of the listing
+	async fn next(&mut self) -> Result<Vec<String>>
+	{
+		match self {
+			PageFeed::Direct { list, img_oldest, suffix_thumb, pb } => {
+				let res = list.fetch_page(img_oldest, pb).await?;
+				let page = strip_thumbs(suffix_thumb, res.thumbs);
+				*img_oldest = page.first().cloned().unwrap_or_default();
+				Ok(page)
+			},
+			PageFeed::Prefetch(rx) => rx.recv().await
+				.unwrap_or_else(|_| Ok(Vec::new())),
+			PageFeed::Warming(first) => {
+				let (feed, page) = first.await.context("failed to fetch the first listing page")?;
+				*self = feed;
+				page
+			},
+		}
+	}
+}
+
+impl status::Stats
+{
+	fn add(&mut self, outcome: &Outcome)
+	{
+		let now = Utc::now();
+		match &outcome.result {
+			Ok(Download { status: Status::Downloaded, .. }) => self.downloaded += 1,
+			Ok(Download { status: Status::Exists | Status::Identical, .. }) => self.exists += 1,
+			Err(err) => {
+				self.failed += 1;
+				self.last_error = Some((now, format!("{}: {:#}", outcome.img, err)));
+				return;
+			},
+		}
+		self.last_success = Some(now);
+	}
+}
+
+/// How the search for images went
+struct Scan {
+	truncated: bool,
+	/// All ids of the listing, if it was walked from the newest to the oldest image
+	listed: Option<BTreeSet<String>>,
+}
+
+/// Image ordered by capture time, then by id
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Recent(Option<DateTime<Utc>>, String);
+
+/// Pass on images from `rx` to `tx`, newest first, as fast as `tx` accepts them
+async fn prioritize(rx: async_channel::Receiver<Msg>, tx: async_channel::Sender<Msg>, re: Option<Regex>, tz: Option<Tz>)
+{
+	let mut heap = BinaryHeap::new();
+	let mut input_done = false;
+
+	loop {
+		let next = heap.peek().map(|Recent(_, img): &Recent| img.clone());
+		tokio::select! {
+			msg = rx.recv(), if !input_done => match msg {
+				Ok(Msg::Image(img)) => heap.push(Recent(image_time(re.as_ref(), tz, &img), img)),
+				// rows are not reordered
+				Ok(msg @ Msg::Row(_)) => if tx.send(msg).await.is_err() {
+					return;
+				},
+				Err(_) => input_done = true,
+			},
+			res = tx.send(Msg::Image(next.clone().unwrap_or_default())), if next.is_some() => {
+				if res.is_err() {
+					return;
+				}
+				heap.pop();
+			},
+			// dropping `tx` ends the workers
+			else => break,
+		}
+	}
+}
+
+async fn feed_listing(opt: &Opt, client: &Client, pb: &ProgressBar, img_tx: &async_channel::Sender<Msg>, samples: &SizeSamples, tracker: Option<&Tracker>, backfill: Option<&Backfill>) -> Result<Scan>
+{
+	let url_base = &base_url(&opt.url);
+	let list = Listing::new(client, url_base)?;
+	let mut img_oldest = tracker.map(Tracker::cursor).unwrap_or_default();
+	let mut page_count = 0;
+	let mut feed = PageFeed::new(opt, list, &img_oldest, pb);
+	let mut seen = BTreeSet::new();
+	let mut downsample = opt.downsample.map(|interval| Downsample::new(interval, opt.timezone));
+	let seeded = seed_images(opt).await?;
+	let allowlist = match &opt.image_id_allowlist_file {
+		Some(path) => Some(load_allowlist(path).await?),
+		None => None,
+	};
+	let identical = match opt.skip_identical_consecutive {
+		true => load_identical(&opt.download_dir.join(IDENTICAL_FILE)).await?,
+		false => BTreeSet::new(),
+	};
+	let since = match opt.since_file_mtime {
+		true => {
+			let (dir, suffix) = (image_root(opt), opt.suffix_full.clone());
+			tokio::task::spawn_blocking(move || archive::newest_mtime(&dir, &suffix)).await??
+				.map(DateTime::<Utc>::from)
+		},
+		false => None,
+	};
+	if let Some(since) = since {
+		pb.println(format!("Only looking for images after {}", since.with_timezone(&Local)));
+	}
+	let spill_dir = opt.low_memory
+		.then(|| std::env::temp_dir().join(format!("camscrub-{}", std::process::id())));
+	let mut pages = spill::Pages::new(spill_dir).await?;
+
+	pb.println(format!("Searching image URLs in {} ...", url_base));
+	let from_newest = img_oldest.is_empty();
+	let mut walked = false;
+	// one image of each of the first pages, to estimate the download size from
+	let mut sampled = Vec::new();
+	let truncated = loop {
+		if deadline_passed(opt) {
+			break true;
+		}
+		wait_for_drain(img_tx).await;
+		let mut img_urls = feed.next().await?;
 
-		let img_count = img_urls.len();
 		img_oldest = img_urls.first()
 			.cloned()
 			.unwrap_or_default();
+		page_count += 1;
+		// most likely a wrong webcam name, which the server answers with an empty listing
+		if page_count == 1 && from_newest && img_oldest.is_empty() {
+			if opt.exit_on_empty_listing.is_some() {
+				return Err(EmptyListing.into());
+			}
+			pb.println("no images listed, is the webcam name right?");
+			// not a walk to delete unlisted images by
+			return Ok(Scan { truncated: false, listed: None });
+		}
+
+		// overlapping pages would process images twice
+		img_urls.retain(|img| seen.insert(img.clone()));
+
+		// pages get older, so once all of a page is in the archive, the rest is as well
+		let is_new = |img: &String| match (since, image_time(opt.image_name_regex.as_ref(), opt.timezone, img)) {
+			(Some(since), Some(time)) => time > since,
+			_ => true,
+		};
+		let caught_up = since.is_some() && !img_urls.is_empty() && !img_urls.iter().any(is_new);
+		img_urls.retain(is_new);
+		if !seeded.is_empty() {
+			img_urls.retain(|img| {
+				let path = image_file(opt, &(img.clone() + &opt.suffix_full));
+				!seeded.contains(path.strip_prefix(&opt.download_dir).unwrap_or(&path))
+			});
+		}
+		if let Some(allowlist) = &allowlist {
+			img_urls.retain(|img| allowlist.contains(img));
+		}
+		// already skipped as identical to the frame before
+		img_urls.retain(|img| !identical.contains(img));
+
+		if let Some(downsample) = &mut downsample {
+			img_urls = downsample.page(opt.image_name_regex.as_ref(), img_urls, img_oldest.is_empty() || caught_up || opt.max_pages == Some(page_count));
+		}
+
+		if let Some(backfill) = backfill {
+			img_urls.retain(|img| backfill.is_pending(img));
+		}
+
+		if let Some(tracker) = tracker {
+			tracker.push_page(&img_oldest, &img_urls);
+			img_urls.retain(|img| !tracker.is_done(img));
+			if page_count % opt.checkpoint_pages.max(1) == 0 {
+				tracker.save().await?;
+			}
+		}
 
 		pb.set_message(format!("search & load... (oldest: {})", img_oldest));
-		pb.inc_length(img_count as _);
+		pb.inc_length(img_urls.len() as _);
 
-		for img_url in img_urls.into_iter() {
-			img_tx.send(img_url)
-				.await.context("failed to distribute image URLs")?;
+		if let (true, Some(img)) = (opt.probe_then_confirm && sampled.len() < CONFIRM_SAMPLES, img_urls.first()) {
+			sampled.push(img.clone());
+		}
+		if opt.order == Order::Oldest || opt.check_space || opt.probe_then_confirm {
+			pages.push(img_urls).await?;
+		} else {
+			send_images(opt, img_tx, img_urls).await?;
 		}
 
-		if img_oldest.is_empty() {
+		if img_oldest.is_empty() || caught_up {
 			pb.set_message(format!("loading... (oldest: {})", img_oldest));
-			break;
+			walked = from_newest && !caught_up;
+			break false;
+		}
+		if opt.max_pages == Some(page_count) {
+			pb.set_message(format!("loading... (oldest: {})", img_oldest));
+			break true;
+		}
+	};
+
+	// pages arrive newest first, but each is sorted oldest first
+	let mut order: Box<dyn Iterator<Item = usize>> = match opt.order {
+		Order::Newest => Box::new(0..pages.len()),
+		Order::Oldest => Box::new((0..pages.len()).rev()),
+	};
+
+	if opt.probe_then_confirm {
+		confirm_download(opt, client, pb, &sampled).await?;
+		pb.set_message(format!("loading... (oldest: {})", img_oldest));
+	}
+	if opt.check_space {
+		if let Some(index) = order.next() {
+			let page = pages.take(index).await?;
+			let count = page.len() as u64;
+			if let Some(backfill) = backfill {
+				backfill.dispatch(&page);
+			}
+			if let Some(frames) = &client.frames {
+				frames.dispatch(&page);
+			}
+			send_images(opt, img_tx, page).await?;
+			check_space(opt, pb, samples, count).await?;
+		}
+	}
+	for index in order {
+		let page = pages.take(index).await?;
+		if let Some(backfill) = backfill {
+			backfill.dispatch(&page);
+		}
+		if let Some(frames) = &client.frames {
+			frames.dispatch(&page);
+		}
+		send_images(opt, img_tx, page).await?;
+	}
+
+	// only a complete walk tells what the server no longer has
+	if let (Some(allowlist), true) = (&allowlist, walked) {
+		let missing: Vec<_> = allowlist.iter().filter(|img| !seen.contains(*img)).collect();
+		if !missing.is_empty() {
+			pb.println(format!("{} allowlisted images are not listed:", missing.len()));
+			for img in missing {
+				pb.println(format!("  {}", img));
+			}
 		}
 	}
 
-	// Terminate tasks
-	for id in task_range {
-		img_tx.send(Default::default()).await.ok();
+	Ok(Scan { truncated, listed: walked.then_some(seen) })
+}
+
+/// Image ids of an allowlist file, one per line
+async fn load_allowlist(path: &Path) -> Result<BTreeSet<String>>
+{
+	let data = fs::read_to_string(path).await
+		.with_context(|| format!("failed to read allowlist {}", path.display()))?;
+	Ok(data.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty())
+		.map(str::to_owned)
+		.collect())
+}
+
+/// Ids of the images skipped by --skip-identical-consecutive in earlier runs
+async fn load_identical(path: &Path) -> Result<BTreeSet<String>>
+{
+	match fs::read_to_string(path).await {
+		Ok(data) => Ok(data.lines()
+			.filter_map(|line| line.split_once('\t'))
+			.map(|(img, _)| img.to_owned())
+			.collect()),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+		Err(err) => Err(err)
+			.with_context(|| format!("failed to read {}", path.display())),
 	}
-	// ..and await their end
-	for task in tasks {
-		task.await.ok();
+}
+
+/// Picks the earliest image of each time bucket from pages listed newest first
+struct Downsample {
+	interval: i64,
+	tz: Option<Tz>,
+	/// Earliest image of the oldest bucket so far, which might continue on the next page
+	pending: Option<(i64, String)>,
+}
+
+impl Downsample
+{
+	fn new(interval: time::Duration, tz: Option<Tz>) -> Self
+	{
+		Downsample { interval: interval.as_secs() as i64, tz, pending: None }
 	}
 
-	let end_msg = if pb.position() == pb.length() {
-		"Download complete!"
-	} else {
-		"Download partially complete (some errors occurred)!"
+	/// Images of `page` to keep; pass `last` for the final page to flush the pending image
+	fn page(&mut self, re: Option<&Regex>, page: Vec<String>, last: bool) -> Vec<String>
+	{
+		let mut keep = Vec::new();
+		let mut firsts: Vec<(i64, String)> = Vec::new();
+		for img in page {
+			// buckets align to local wall-clock time, so `1d` starts at midnight
+			let bucket = match image_time(re, self.tz, &img) {
+				Some(time) => wall_clock(self.tz, time).timestamp().div_euclid(self.interval),
+				None => {
+					keep.push(img);
+					continue;
+				},
+			};
+			if !firsts.iter().any(|(b, _)| *b == bucket) {
+				firsts.push((bucket, img));
+			}
+		}
+		firsts.sort_by_key(|(bucket, _)| *bucket);
+
+		if let Some((bucket, img)) = self.pending.take() {
+			if !firsts.iter().any(|(b, _)| *b == bucket) {
+				keep.push(img);
+			}
+		}
+		if !firsts.is_empty() {
+			self.pending = Some(firsts.remove(0));
+		}
+		keep.extend(firsts.into_iter().map(|(_, img)| img));
+		if last {
+			keep.extend(self.pending.take().map(|(_, img)| img));
+		}
+		keep
+	}
+}
+
+/// Wait while the workers are well behind, so the listing doesn't run ahead of the downloads
+async fn wait_for_drain(img_tx: &async_channel::Sender<Msg>)
+{
+	let capacity = match img_tx.capacity() {
+		Some(capacity) => capacity,
+		None => return,
 	};
-	pb.finish_with_message(end_msg);
+	if img_tx.len() < capacity * 3 / 4 {
+		return;
+	}
+	while img_tx.len() > capacity / 4 && !img_tx.is_closed() {
+		time::sleep(DRAIN_POLL).await;
+	}
+}
+
+const DRAIN_POLL: time::Duration = time::Duration::from_millis(100);
 
+/// Paths of images in the --seed-from-directory folders, relative to them
+async fn seed_images(opt: &Opt) -> Result<HashSet<PathBuf>>
+{
+	let mut seeded = HashSet::new();
+	for dir in &opt.seed_from_directory {
+		let (dir, suffix) = (dir.clone(), opt.suffix_full.clone());
+		seeded.extend(tokio::task::spawn_blocking(move || archive::scan(&dir, &suffix)).await??);
+	}
+	Ok(seeded)
+}
+
+async fn send_images(opt: &Opt, img_tx: &async_channel::Sender<Msg>, imgs: Vec<String>) -> Result<()>
+{
+	for img in imgs {
+		if deadline_passed(opt) {
+			break;
+		}
+		img_tx.send(Msg::Image(img))
+			.await.context("failed to distribute image URLs")?;
+	}
 	Ok(())
 }
 
-enum Status {
-	Downloaded,
-	Exists,
+/// Sizes of the first downloads, for estimating the size of the rest
+#[derive(Default)]
+struct SizeSamples {
+	processed: AtomicU64,
+	downloaded: AtomicU64,
+	bytes: AtomicU64,
 }
 
-async fn download(client: &http::Client, url: &http::Url, path: &Path) -> Result<Status>
+impl SizeSamples
 {
-	let mtime = fs::metadata(path).await
-		.and_then(|md| md.modified())
-		.map(DateTime::<Local>::from)
-		.unwrap_or(Local.timestamp(0, 0))
-		.with_timezone(&Utc);
+	fn add(&self, result: &Result<Download>)
+	{
+		if let Ok(Download { status: Status::Downloaded, size, .. }) = result {
+			self.bytes.fetch_add(*size, Ordering::Relaxed);
+			self.downloaded.fetch_add(1, Ordering::Relaxed);
+		}
+		self.processed.fetch_add(1, Ordering::Release);
+	}
+}
 
-	let resp = client.get(url.clone())
-		.header("If-Modified-Since", mtime.to_rfc2822())
-		.send()
-		.await.context("failed to send download request")?
-		.error_for_status()
-		.with_context(|| format!("failed to download {}", &url))?;
+/// Print the rate of images and bytes with an ETA every `interval`, until aborted
+async fn print_throughput(interval: time::Duration, pb: Arc<ProgressBar>, samples: Arc<SizeSamples>)
+{
+	let mut ticks = time::interval(interval);
+	ticks.tick().await;
+	let (mut pos, mut bytes) = (pb.position(), 0);
+	loop {
+		ticks.tick().await;
+		let (new_pos, new_bytes) = (pb.position(), samples.bytes.load(Ordering::Relaxed));
+		let secs = interval.as_secs_f64();
+		let imgs_rate = (new_pos - pos) as f64 / secs;
+		let bytes_rate = (new_bytes - bytes) as f64 / secs;
+		(pos, bytes) = (new_pos, new_bytes);
 
-	if resp.status() == http::StatusCode::NOT_MODIFIED {
-		return Ok(Status::Exists);
+		let left = pb.length().saturating_sub(pos);
+		let eta = match imgs_rate > 0.0 {
+			true => humantime::format_duration(time::Duration::from_secs((left as f64 / imgs_rate) as u64)).to_string(),
+			false => "unknown".to_owned(),
+		};
+		pb.println(format!("{:.1} images/s, {:.1} KiB/s, {} left, ETA {}", imgs_rate, bytes_rate / 1024.0, left, eta));
 	}
+}
 
-	let mtime = resp.headers().get("Last-Modified")
-		.context("missing Last-Modified header")
-		.and_then(|hv|  hv.to_str().context("invalid header value"))
-		.and_then(|value| DateTime::parse_from_rfc2822(&value)
-				.map(|dt| dt.with_timezone(&Utc))
-				.context("invalid modify time"))
-		.unwrap_or_else(|_| Utc::now());
+const SPACE_SAMPLES: u64 = 8;
 
-	let path_dir = path.parent().unwrap();
-	fs::create_dir_all(&path_dir).await
-		.with_context(|| format!("failed to create directory {}", path_dir.display()))?;
+/// Compare the estimated size of the remaining images against the free space
+async fn check_space(opt: &Opt, pb: &ProgressBar, samples: &SizeSamples, sent: u64) -> Result<()>
+{
+	while samples.downloaded.load(Ordering::Relaxed) < SPACE_SAMPLES
+		&& samples.processed.load(Ordering::Acquire) < sent {
+		time::sleep(time::Duration::from_millis(100)).await;
+	}
 
-	let mut file = fs::File::create(path).await
-		.with_context(|| format!("failed to create image file {}", path.display()))?;
+	let downloaded = samples.downloaded.load(Ordering::Relaxed);
+	if downloaded == 0 {
+		pb.println("skipping space check, no new images to estimate from");
+		return Ok(());
+	}
+	let avg_size = samples.bytes.load(Ordering::Relaxed) / downloaded;
+	let needed = pb.length().saturating_sub(pb.position()) * avg_size;
 
-	if let Some(len) = resp.content_length() {
-		file.set_len(len).await.ok();
+	// the download folder might not exist yet
+	let dir = opt.download_dir.ancestors()
+		.find(|dir| dir.exists())
+		.unwrap_or_else(|| Path::new("."));
+	let stat = nix::sys::statvfs::statvfs(dir)
+		.with_context(|| format!("failed to get free space of {}", dir.display()))?;
+	let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+
+	if needed > free {
+		pb.println(format!("not enough free space: about {} MiB needed, {} MiB available",
+			needed >> 20, free >> 20));
+		anyhow::ensure!(opt.yes, "not enough free space (use --yes to continue anyway)");
 	}
+	Ok(())
+}
 
-	let mut stream = resp.bytes_stream();
-	while let Some(chunk) = stream.next().await {
-		file.write_all_buf(&mut chunk?).await
-			.context("failed to write")?;
+/// Listed images whose size is asked for with --probe-then-confirm
+const CONFIRM_SAMPLES: usize = 8;
+
+/// Show how much the run would download and ask whether to go on, unless --yes is given
+async fn confirm_download(opt: &Opt, client: &Client, pb: &ProgressBar, sampled: &[String]) -> Result<()>
+{
+	let mut sizes = Vec::new();
+	for img in sampled {
+		let url = image_location(opt, &image_base(opt), img)?;
+		let resp = client.send(client.head(url).await?).await
+			.and_then(|resp| Ok(resp.error_for_status()?));
+		match resp {
+			// not content_length(), which is that of the empty body of a HEAD response
+			Ok(resp) => sizes.extend(resp.headers().get(http::header::CONTENT_LENGTH)
+				.and_then(|hv| hv.to_str().ok()?.parse::<u64>().ok())
+				.filter(|&len| len > 0)),
+			Err(err) => pb.println(format!("failed to get the size of {}: {:#}", img, err)),
+		}
 	}
-	file.flush().await.context("failed to flush")?;
 
-	// set modification date from server
-	let tv = TimeVal::milliseconds(mtime.timestamp_millis());
-	nix::sys::stat::utimes(path, &tv, &tv).ok();
+	let count = pb.length();
+	let estimate = match sizes.is_empty() {
+		true => "unknown size".to_owned(),
+		false => {
+			let avg_size = sizes.iter().sum::<u64>() / sizes.len() as u64;
+			format!("about {} MiB", (count * avg_size) >> 20)
+		},
+	};
+	pb.println(format!("{} listed images to check, {} if none is archived yet", count, estimate));
+	if opt.yes {
+		return Ok(());
+	}
+
+	let tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty")
+		.context("no terminal to ask on (use --yes to continue anyway)")?;
+	pb.set_message("waiting for confirmation...");
+	let answer = tokio::task::spawn_blocking(move || {
+		use std::io::{BufRead, Write};
+		let mut tty = tty;
+		write!(tty, "\rDownload them? [y/N] ")?;
+		let mut answer = String::new();
+		std::io::BufReader::new(tty).read_line(&mut answer)?;
+		Ok::<_, std::io::Error>(answer)
+	}).await?.context("failed to read the answer")?;
+	anyhow::ensure!(matches!(answer.trim(), "y" | "Y" | "yes"), "download not confirmed");
+	Ok(())
+}
+
+async fn feed_stdin(opt: &Opt, pb: &ProgressBar, img_tx: &async_channel::Sender<Msg>) -> Result<Scan>
+{
+	use io::AsyncBufReadExt;
+
+	pb.println("Reading image ids from stdin ...");
+	let mut lines = io::BufReader::new(io::stdin()).lines();
+	while let Some(line) = lines.next_line().await.context("failed to read stdin")? {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		let img = match opt.ndjson {
+			true => match serde_json::from_str::<StdinImage>(line) {
+				Ok(StdinImage { id }) => id,
+				Err(err) => {
+					pb.println(format!("skipping malformed line {:?}: {}", snippet(line), err));
+					continue;
+				},
+			},
+			false => line.to_owned(),
+		};
+		if deadline_passed(opt) {
+			return Ok(Scan { truncated: true, listed: None });
+		}
 
-	Ok(Status::Downloaded)
+		pb.inc_length(1);
+		img_tx.send(Msg::Image(img))
+			.await.context("failed to distribute image URLs")?;
+	}
+	pb.set_message("loading...");
+
+	Ok(Scan { truncated: false, listed: None })
+}
+
+/// Line of --ndjson input
+#[derive(Deserialize)]
+struct StdinImage {
+	id: String,
+}
+
+async fn feed_csv(opt: &Opt, csv: &Path, pb: &ProgressBar, img_tx: &async_channel::Sender<Msg>) -> Result<Scan>
+{
+	let data = fs::read(csv).await
+		.with_context(|| format!("failed to read {}", csv.display()))?;
+	let mut reader = csv::ReaderBuilder::new()
+		.has_headers(false)
+		.flexible(true)
+		.from_reader(&data[..]);
+
+	pb.println(format!("Reading images from {} ...", csv.display()));
+	for (index, row) in reader.records().enumerate() {
+		let row = match row {
+			Ok(row) => row,
+			Err(err) => {
+				pb.println(format!("skipping malformed row {}: {}", index + 1, err));
+				continue;
+			},
+		};
+		let field = |i| row.get(i).map(str::trim).filter(|field| !field.is_empty());
+		let img = match field(0) {
+			Some(img) => img.to_owned(),
+			None => continue,
+		};
+		// the optional header
+		if index == 0 && img == "id" {
+			continue;
+		}
+		let url = match field(1).map(http::Url::parse).transpose() {
+			Ok(url) => url,
+			Err(err) => {
+				pb.println(format!("skipping row {} with invalid URL: {}", index + 1, err));
+				continue;
+			},
+		};
+		let path = field(2).map(|path| opt.download_dir.join(path));
+		if deadline_passed(opt) {
+			return Ok(Scan { truncated: true, listed: None });
+		}
+
+		pb.inc_length(1);
+		img_tx.send(Msg::Row(Row { img, url, path }))
+			.await.context("failed to distribute image URLs")?;
+	}
+	pb.set_message("loading...");
+
+	Ok(Scan { truncated: false, listed: None })
+}
+
+/// Whether images named with `suffix` are JPEGs
+fn is_jpeg_suffix(suffix: &str) -> bool
+{
+	let suffix = suffix.to_ascii_lowercase();
+	suffix.ends_with(".jpg") || suffix.ends_with(".jpeg")
+}
+
+/// Remove archived images that don't decode and queue them for download again
+async fn feed_corrupt(opt: &Opt, pb: &ProgressBar, img_tx: &async_channel::Sender<Msg>) -> Result<Scan>
+{
+	let root = image_root(opt);
+	pb.println(format!("Checking images in {} ...", root.display()));
+	let (dir, suffix) = (root.clone(), opt.suffix_full.clone());
+	let corrupt = tokio::task::spawn_blocking(move || {
+		let mut corrupt = Vec::new();
+		for rel in archive::scan(&dir, &suffix)? {
+			if archive::check_jpeg(&dir.join(&rel), true)? != archive::JpegCheck::Valid {
+				corrupt.push(rel);
+			}
+		}
+		Ok::<_, anyhow::Error>(corrupt)
+	}).await??;
+	pb.println(format!("Found {} corrupt images", corrupt.len()));
+
+	// download() replaces them only once a new copy is complete
+	for rel in corrupt {
+		let img = layout_id(opt, &rel);
+		pb.inc_length(1);
+		img_tx.send(Msg::Image(img))
+			.await.context("failed to distribute image URLs")?;
+	}
+	pb.set_message("repairing...");
+
+	Ok(Scan { truncated: false, listed: None })
+}
+
+async fn count(opt: Opt) -> Result<()>
+{
+	let url_base = base_url(&opt.url);
+	let client = build_client(&opt).await?;
+	let list = Listing::new(&client, &url_base)?;
+
+	let mut suffix_thumb = opt.suffix_thumb.clone();
+	let mut img_oldest = String::new();
+	let mut img_newest = None;
+	let mut seen = BTreeSet::new();
+	loop {
+		let res = list.fetch(&img_oldest).await?;
+		let imgs = strip_thumbs(&mut suffix_thumb, res.thumbs);
+		let first = match imgs.first() {
+			Some(first) => first.clone(),
+			None => break,
+		};
+
+		seen.extend(imgs.iter().cloned());
+		if img_newest.is_none() {
+			img_newest = imgs.last().cloned();
+		}
+		img_oldest = first;
+	}
+
+	println!("{}", seen.len());
+	if let Some(img_newest) = img_newest {
+		let re = opt.image_name_regex.as_ref();
+		let time = |img| image_time(re, opt.timezone, img)
+			.map(|time| match opt.timezone {
+				Some(tz) => time.with_timezone(&tz).to_string(),
+				None => time.with_timezone(&Local).to_string(),
+			})
+			.unwrap_or_else(|| "unknown time".to_owned());
+		eprintln!("newest: {} ({})", img_newest, time(&img_newest));
+		eprintln!("oldest: {} ({})", img_oldest, time(&img_oldest));
+	}
+	Ok(())
+}
+
+async fn diff(opt: Opt) -> Result<()>
+{
+	let url_base = base_url(&opt.url);
+	let client = build_client(&opt).await?;
+	let list = Listing::new(&client, &url_base)?;
+
+	let local: BTreeSet<_> = {
+		let (dir, suffix) = (opt.download_dir.clone(), opt.suffix_full.clone());
+		tokio::task::spawn_blocking(move || archive::scan(&dir, &suffix)).await??
+			.into_iter()
+			.collect()
+	};
+
+	let mut missing = Vec::new();
+	let mut both = BTreeSet::new();
+	for img in list_all(&opt, &list).await? {
+		let path = image_file(&opt, &(img.clone() + &opt.suffix_full));
+		let rel = path.strip_prefix(&opt.download_dir).unwrap_or(&path).to_owned();
+		if local.contains(&rel) {
+			both.insert(rel);
+		} else {
+			missing.push(img);
+		}
+	}
+	let extra: Vec<_> = local.difference(&both).collect();
+
+	println!("On both: {}", both.len());
+	println!("Missing locally: {}", missing.len());
+	if opt.diff_verbose {
+		for img in &missing {
+			println!("  {}", img);
+		}
+	}
+	println!("Extra locally: {}", extra.len());
+	if opt.diff_verbose {
+		for path in &extra {
+			println!("  {}", path.display());
+		}
+	}
+	Ok(())
+}
+
+/// What a run would do with an image
+enum Change {
+	New,
+	Updated,
+	Current,
+}
+
+async fn dry_run_diff(opt: Opt) -> Result<()>
+{
+	let url_base = base_url(&opt.url);
+	let client = build_client(&opt).await?;
+	let list = Listing::new(&client, &url_base)?;
+
+	let imgs = list_all(&opt, &list).await?;
+	let img_base = image_base(&opt);
+	let checks = imgs.iter().map(|img| {
+		let img_path = img.clone() + &opt.suffix_full;
+		let url = image_location(&opt, &img_base, img).map(|url| download_url(&opt, &url));
+		let path = image_file(&opt, &img_path);
+		let (opt, client) = (&opt, &client);
+		async move {
+			let url = url?;
+			let change = check_change(opt, client, &url, &path).await
+				.with_context(|| format!("failed to check {}", img))?;
+			Ok::<_, anyhow::Error>((img, change))
+		}
+	});
+	let mut checks = futures_util::StreamExt::buffer_unordered(futures_util::stream::iter(checks), 4);
+
+	let (mut new, mut updated, mut current) = (Vec::new(), Vec::new(), 0);
+	while let Some(check) = checks.next().await {
+		match check? {
+			(img, Change::New) => new.push(img),
+			(img, Change::Updated) => updated.push(img),
+			(_, Change::Current) => current += 1,
+		}
+	}
+	new.sort();
+	updated.sort();
+
+	println!("New: {}", new.len());
+	if opt.diff_verbose {
+		for img in &new {
+			println!("  {}", img);
+		}
+	}
+	println!("Updated: {}", updated.len());
+	if opt.diff_verbose {
+		for img in &updated {
+			println!("  {}", img);
+		}
+	}
+	println!("Current: {}", current);
+	Ok(())
+}
+
+/// Whether the image at `url` is missing locally or changed since, asked like `download()` does
+async fn check_change(opt: &Opt, client: &Client, url: &http::Url, path: &Path) -> Result<Change>
+{
+	let mtime = match fs::metadata(path).await {
+		Ok(md) => DateTime::<Utc>::from(md.modified()?),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Change::New),
+		Err(err) => return Err(err.into()),
+	};
+	if matches!(mtime_source(opt), MtimeSource::Id | MtimeSource::Min) {
+		return Ok(Change::Current);
+	}
+
+	let req = client.head(url.clone()).await?
+		.header("If-Modified-Since", mtime.to_rfc2822());
+	let resp = client.send(req)
+		.await.context("failed to send request")?
+		.error_for_status()?;
+	if resp.status() == http::StatusCode::NOT_MODIFIED {
+		return Ok(Change::Current);
+	}
+	// not every server answers HEAD conditionally
+	Ok(match last_modified(&resp) {
+		Ok(modified) if modified <= mtime => Change::Current,
+		_ => Change::Updated,
+	})
+}
+
+/// Newest image id of the listing, if it exists locally already
+async fn newest_if_archived(opt: &Opt, client: &Client, url_base: &http::Url, pb: &ProgressBar) -> Result<Option<String>>
+{
+	let list = Listing::new(client, url_base)?;
+	let mut suffix_thumb = opt.suffix_thumb.clone();
+	let res = list.fetch_page("", pb).await?;
+	// pages are sorted oldest first
+	let img = match strip_thumbs(&mut suffix_thumb, res.thumbs).pop() {
+		Some(img) => img,
+		None => return Ok(None),
+	};
+	let path = image_file(opt, &(img.clone() + &opt.suffix_full));
+	match fs::metadata(&path).await {
+		Ok(_) => Ok(Some(img)),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(err)
+			.with_context(|| format!("failed to check {}", path.display())),
+	}
+}
+
+/// Worker counts tried by --concurrency-auto, in order
+const CALIBRATION_LEVELS: [usize; 5] = [1, 2, 4, 8, 16];
+/// Downloads per worker at each calibration level
+const CALIBRATION_ROUNDS: usize = 2;
+
+/// Worker count with the best throughput, raised only while it clearly helps and failures do not rise
+async fn calibrate(opt: &Opt, client: &Client, url_base: &http::Url, pb: &ProgressBar) -> Result<usize>
+{
+	let list = Listing::new(client, url_base)?;
+	let mut suffix_thumb = opt.suffix_thumb.clone();
+	let imgs = strip_thumbs(&mut suffix_thumb, list.fetch_page("", pb).await?.thumbs);
+	anyhow::ensure!(!imgs.is_empty(), "no images listed to calibrate with");
+
+	let img_base = image_base(opt);
+	// pages are sorted oldest first
+	let mut imgs = imgs.iter().rev().cycle();
+	let (mut best, mut best_rate, mut base_error_rate) = (1, 0.0, None);
+	for workers in CALIBRATION_LEVELS {
+		let fetches = (&mut imgs).take(workers * CALIBRATION_ROUNDS).map(|img| {
+			let url = image_location(opt, &img_base, img);
+			async move {
+				let url = url?;
+				retrying(opt, client, pb, &format!("calibration download of {}", img), || receive(opt, client, &url)).await
+			}
+		});
+		let started = time::Instant::now();
+		let mut sizes: Vec<_> = futures_util::StreamExt::buffer_unordered(futures_util::stream::iter(fetches), workers)
+			.collect().await;
+		// the workers would fail the same way
+		if let Some(at) = sizes.iter().position(|size| matches!(size, Err(err) if err.is::<TokenRefresh>())) {
+			return Err(sizes.swap_remove(at).unwrap_err());
+		}
+		let rate = sizes.iter().flatten().sum::<u64>() as f64 / started.elapsed().as_secs_f64();
+		let failed = sizes.iter().filter(|size| size.is_err()).count();
+		pb.println(format!("calibration: {} workers, {:.0} KiB/s, {} of {} failed", workers, rate / 1024.0, failed, sizes.len()));
+
+		let error_rate = failed as f64 / sizes.len() as f64;
+		if error_rate > *base_error_rate.get_or_insert(error_rate) || rate < best_rate * 1.1 {
+			break;
+		}
+		best = workers;
+		best_rate = rate;
+	}
+	pb.println(format!("Using {} workers", best));
+	Ok(best)
+}
+
+/// All distinct image ids of the listing, newest page first
+async fn list_all(opt: &Opt, list: &Listing) -> Result<Vec<String>>
+{
+	let mut suffix_thumb = opt.suffix_thumb.clone();
+	let mut img_oldest = String::new();
+	let mut seen = BTreeSet::new();
+	let mut imgs = Vec::new();
+	loop {
+		let res = list.fetch(&img_oldest).await?;
+		let page = strip_thumbs(&mut suffix_thumb, res.thumbs);
+		img_oldest = match page.first() {
+			Some(first) => first.clone(),
+			None => break,
+		};
+		imgs.extend(page.into_iter().filter(|img| seen.insert(img.clone())));
+	}
+	Ok(imgs)
+}
+
+async fn probe(opt: Opt) -> Result<()>
+{
+	let url_base = base_url(&opt.url);
+	let client = build_client(&opt).await?;
+	let list = Listing::new(&client, &url_base)?;
+
+	println!("Listing {} ...", list.url);
+	let res = list.fetch("").await
+		.context("probe: listing failed")?;
+	println!("  ok: {} thumbs", res.thumbs.len());
+
+	let (first, last) = match (res.thumbs.first(), res.thumbs.last()) {
+		(Some(first), Some(last)) => (first, last),
+		_ => anyhow::bail!("probe: listing is empty"),
+	};
+	println!("  first: {}", first);
+	println!("  last:  {}", last);
+
+	let suffix = opt.suffix_thumb.as_deref()
+		.or_else(|| detect_suffix(first))
+		.unwrap_or_default();
+	let img = first.strip_suffix(suffix).unwrap_or(first);
+	println!("  image id: {} (thumb suffix: {:?})", img, suffix);
+
+	let url = image_location(&opt, &image_base(&opt), img)?;
+	println!("Downloading {} ...", url);
+	let resp = client.send(client.get(url).await?.timeout(REQUEST_TIMEOUT))
+		.await.context("probe: failed to send download request")?
+		.error_for_status()
+		.context("probe: image download failed")?;
+	let content_type = resp.headers().get(http::header::CONTENT_TYPE)
+		.and_then(|hv| hv.to_str().ok())
+		.unwrap_or("unknown")
+		.to_owned();
+	let body = resp.bytes().await.context("probe: failed to read image")?;
+	println!("  ok: {} bytes of {}", body.len(), content_type);
+
+	println!("Probe successful!");
+	Ok(())
+}
+
+const TEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+async fn test_url(opt: Opt) -> Result<()>
+{
+	let url_base = base_url(&opt.url);
+	let client = build_client(&opt).await?;
+	let list = Listing::new(&client, &url_base)?;
+
+	let mut ok = true;
+	for (url, listing) in [(opt.url.clone(), false), (list.url.clone(), true)] {
+		print!("{} ... ", url);
+		let mut req = client.get(url).await?.timeout(TEST_TIMEOUT);
+		if listing {
+			req = req.query(&list.list_req).query(&[("img", "")]);
+		}
+
+		let start = time::Instant::now();
+		let res = async {
+			let resp = req.send().await.context("failed to send request")?;
+			let status = resp.status();
+			let body = resp.text().await.context("failed to read response")?;
+			Ok::<_, anyhow::Error>((status, body))
+		}.await;
+		let latency = start.elapsed().as_millis();
+
+		let (status, body) = match res {
+			Ok(res) => res,
+			Err(err) => {
+				println!("{:#} after {}ms", err, latency);
+				ok = false;
+				continue;
+			},
+		};
+		print!("{} in {}ms", status, latency);
+		ok &= status.is_success();
+		if listing {
+			match serde_json::from_str::<ListResponse>(&body) {
+				Ok(res) => print!(", {} thumbs", res.thumbs.len()),
+				Err(err) => {
+					print!(", invalid JSON: {}", err);
+					ok = false;
+				},
+			}
+		}
+		println!();
+	}
+
+	anyhow::ensure!(ok, "URL test failed");
+	Ok(())
+}
+
+/// Pack `paths` into `camscrub-<time>.tar.*` in the download folder
+async fn post_archive(opt: &Opt, pb: &ProgressBar, format: PostArchive, paths: &[PathBuf]) -> Result<()>
+{
+	if paths.is_empty() {
+		return Ok(());
+	}
+	let (ext, compression) = match format {
+		PostArchive::TarGz => ("tar.gz", archive::Compression::Gzip),
+		PostArchive::TarZst => ("tar.zst", archive::Compression::Zstd),
+	};
+	let dir = opt.download_dir.clone();
+	let out = dir.join(format!("camscrub-{}.{}", wall_clock(opt.timezone, Utc::now()).format("%Y%m%dT%H%M%S"), ext));
+	let mut files: Vec<_> = paths.iter()
+		.map(|path| path.strip_prefix(&dir).unwrap_or(path).to_owned())
+		.collect();
+	files.sort();
+
+	let (remove, tarball) = (opt.remove_after_archive, out.clone());
+	tokio::task::spawn_blocking(move || {
+		archive::pack(&dir, &files, &tarball, compression)?;
+		if remove {
+			for file in &files {
+				std::fs::remove_file(dir.join(file))
+					.with_context(|| format!("failed to remove {}", file.display()))?;
+			}
+		}
+		Ok::<_, anyhow::Error>(())
+	}).await??;
+
+	pb.println(format!("Packed {} images into {}", paths.len(), out.display()));
+	Ok(())
+}
+
+async fn validate(opt: Opt) -> Result<()>
+{
+	let root = image_root(&opt);
+	let suffix = opt.suffix_full.clone();
+	let manifest = opt.checksum_manifest.clone();
+	let bad = tokio::task::spawn_blocking(move || {
+		let mut bad = Vec::new();
+		let imgs = archive::scan(&root, &suffix)?;
+		let jpeg = is_jpeg_suffix(&suffix);
+		if !jpeg {
+			println!("Only JPEGs can be decoded, checking that the images are not empty");
+		}
+		for rel in &imgs {
+			let path = root.join(rel);
+			let check = match jpeg {
+				true => archive::check_jpeg(&path, true)?,
+				false => match std::fs::metadata(&path)?.len() {
+					0 => archive::JpegCheck::Empty,
+					_ => archive::JpegCheck::Valid,
+				},
+			};
+			if check != archive::JpegCheck::Valid {
+				bad.push((format!("{:?}", check).to_lowercase(), path));
+			}
+		}
+		println!("Checked {} images", imgs.len());
+
+		if let Some(manifest) = &manifest {
+			let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+			let data = std::fs::read_to_string(manifest)
+				.with_context(|| format!("failed to read checksum manifest {}", manifest.display()))?;
+			let mut count = 0;
+			for (sha256, name) in data.lines().filter_map(|line| line.split_once("  ")) {
+				let path = base.join(name);
+				match std::fs::read(&path) {
+					Ok(data) if format!("{:x}", Sha256::digest(&data)) == sha256 => (),
+					Ok(_) => bad.push(("checksum mismatch".to_owned(), path)),
+					Err(_) => bad.push(("missing".to_owned(), path)),
+				}
+				count += 1;
+			}
+			println!("Checked {} checksums", count);
+		}
+		Ok::<_, anyhow::Error>(bad)
+	}).await??;
+
+	for (problem, path) in &bad {
+		println!("  {}: {}", problem, path.display());
+	}
+	anyhow::ensure!(bad.is_empty(), "{} problems found", bad.len());
+	println!("Archive is valid!");
+	Ok(())
+}
+
+/// Where downloads go before the run succeeded, if anywhere
+fn staging_dir(opt: &Opt) -> Option<PathBuf>
+{
+	opt.atomic_archive.then(|| opt.staging_dir.clone()
+		.unwrap_or_else(|| opt.download_dir.join(".camscrub-staging")))
+}
+
+fn deadline_passed(opt: &Opt) -> bool
+{
+	opt.deadline.is_some_and(|deadline| time::Instant::now() >= deadline)
+}
+
+/// Time in-flight downloads get to finish after the deadline
+const DEADLINE_GRACE: time::Duration = time::Duration::from_secs(10);
+
+fn parse_deadline(s: &str) -> Result<time::Instant>
+{
+	Ok(time::Instant::now() + humantime::parse_duration(s)?)
+}
+
+/// RFC 3339, or a time in the system time zone like `2026-10-14 18:00[:00]`
+fn parse_datetime(s: &str) -> Result<DateTime<Utc>>
+{
+	if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+		return Ok(time.with_timezone(&Utc));
+	}
+	let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"].iter()
+		.find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+		.context("must be RFC 3339 or like `2026-10-14 18:00`")?;
+	Local.from_local_datetime(&naive)
+		.earliest()
+		.map(|time| time.with_timezone(&Utc))
+		.context("does not exist in the local time zone")
+}
+
+fn parse_interval(s: &str) -> Result<time::Duration>
+{
+	let interval = humantime::parse_duration(s)?;
+	anyhow::ensure!(interval.as_secs() > 0, "must be at least one second");
+	Ok(interval)
+}
+
+fn parse_image_regex(s: &str) -> Result<Regex>
+{
+	let re = Regex::new(s)?;
+	anyhow::ensure!(re.capture_names().flatten().next().is_some(), "regex has no named groups");
+	Ok(re)
+}
+
+fn parse_probability(s: &str) -> Result<f64>
+{
+	let p = f64::from_str(s)?;
+	anyhow::ensure!((0.0..=1.0).contains(&p), "must be between 0.0 and 1.0");
+	Ok(p)
+}
+
+fn parse_query_pair(s: &str) -> Result<(String, String)>
+{
+	let (key, value) = s.split_once('=')
+		.context("must be of the form KEY=VALUE")?;
+	anyhow::ensure!(!key.is_empty(), "key must not be empty");
+	Ok((key.to_owned(), value.to_owned()))
+}
+
+/// HTTP client that authorizes its requests
+#[derive(Clone)]
+struct Client {
+	http: http::Client,
+	oauth: Option<Arc<OAuth>>,
+	/// Shared by all clones, so the retry budget covers the whole run
+	retries: Arc<Retries>,
+	/// HTTP/1.1 only client for --http-version-fallback
+	http1: Option<http::Client>,
+	/// Listing pages of earlier passes for --list-cache-ttl
+	list_cache: Option<Arc<ListCache>>,
+	/// Filled by the redirect policy of the clients for --capture-redirect-chain
+	redirects: Option<Arc<Redirects>>,
+	list_saves: Option<Arc<ListSaves>>,
+	/// Bytes of image bodies received by all clones, for the throughput of the summary
+	meter: Arc<meter::Meter>,
+	/// Replaces `http` for --resolve-dns-once
+	pinned: Option<Arc<PinnedDns>>,
+	#[cfg(feature = "s3")]
+	upload: Option<Arc<upload::Sink>>,
+	dispositions: Option<Arc<Dispositions>>,
+	/// Compares each image with the one before for --skip-identical-consecutive
+	frames: Option<Arc<consecutive::Frames>>,
+}
+
+impl Client
+{
+	async fn get(&self, url: http::Url) -> Result<http::RequestBuilder>
+	{
+		self.authorize(self.http().get(url)).await
+	}
+
+	async fn head(&self, url: http::Url) -> Result<http::RequestBuilder>
+	{
+		// there is no body to time out for --body-timeout
+		self.authorize(self.http().head(url).timeout(REQUEST_TIMEOUT)).await
+	}
+
+	fn http(&self) -> http::Client
+	{
+		match &self.pinned {
+			Some(pinned) => pinned.http(),
+			None => self.http.clone(),
+		}
+	}
+
+	/// Send `req`, once more over HTTP/1.1 if HTTP/2 failed and --http-version-fallback is set
+	async fn send(&self, req: http::RequestBuilder) -> Result<http::Response>
+	{
+		let again = self.http1.as_ref().and_then(|http1| Some((http1, req.try_clone()?)));
+		match req.send().await {
+			Err(err) if again.is_some() && is_http2_error(&err) => {
+				let (http1, req) = again.unwrap();
+				Ok(http1.execute(req.build()?).await?)
+			},
+			res => Ok(res?),
+		}
+	}
+
+	async fn authorize(&self, req: http::RequestBuilder) -> Result<http::RequestBuilder>
+	{
+		Ok(match &self.oauth {
+			Some(oauth) => req.bearer_auth(oauth.token().await
+				.map_err(|err| TokenRefresh(format!("{:#}", err)))?),
+			None => req,
+		})
+	}
+}
+
+async fn build_client(opt: &Opt) -> Result<Client>
+{
+	let redirects = opt.capture_redirect_chain.then(Arc::default);
+	let http = http_client(opt, redirects.as_ref())?;
+
+	let oauth = match (&opt.oauth_token_url, &opt.client_id, &opt.client_secret) {
+		(Some(url), Some(id), Some(secret)) => {
+			let oauth = OAuth::new(http.clone(), url.clone(), id.clone(), secret.clone());
+			oauth.token().await?;
+			Some(Arc::new(oauth))
+		},
+		_ => None,
+	};
+
+	let http1 = match opt.http_version_fallback {
+		true => Some(http_client_builder(opt, redirects.as_ref()).http1_only().build().context("failed to build http client")?),
+		false => None,
+	};
+
+	let list_saves = match &opt.list_response_save {
+		Some(dir) => {
+			fs::create_dir_all(dir).await
+				.with_context(|| format!("failed to create directory {}", dir.display()))?;
+			Some(Arc::new(ListSaves { dir: dir.clone(), pages: AtomicU64::new(0) }))
+		},
+		None => None,
+	};
+
+	let meter = Arc::new(meter::Meter::new(opt.bps_report_window));
+
+	let pinned = match opt.resolve_dns_once {
+		true => {
+			let hosts = [Some(&opt.url), opt.image_base_url.as_ref()].into_iter().flatten()
+				// IP addresses need no lookup
+				.filter_map(|url| Some((url.domain()?.to_owned(), url.port_or_known_default()?)))
+				.collect();
+			let (opt, redirects) = (opt.clone(), redirects.clone());
+			let build = Box::new(move |addrs: &[(String, std::net::SocketAddr)]| {
+				addrs.iter()
+					.fold(http_client_builder(&opt, redirects.as_ref()), |builder, (host, addr)| builder.resolve(host, *addr))
+					.build()
+					.context("failed to build http client")
+			});
+			Some(Arc::new(PinnedDns::new(hosts, build).await?))
+		},
+		false => None,
+	};
+
+	#[cfg(feature = "s3")]
+	let upload = match &opt.s3_bucket {
+		Some(bucket) => Some(Arc::new(upload::Sink::new(bucket, opt.s3_prefix.as_deref(), opt.s3_endpoint.as_deref())?)),
+		None => None,
+	};
+
+	let dispositions = match opt.use_content_disposition {
+		true => Some(Arc::new(Dispositions::load(&opt.download_dir.join(DISPOSITIONS_FILE)).await?)),
+		false => None,
+	};
+
+	let frames = opt.skip_identical_consecutive
+		.then(|| Arc::new(consecutive::Frames::new(&opt.download_dir.join(IDENTICAL_FILE))));
+
+	Ok(Client {
+		http, oauth, retries: Arc::new(Retries::new(opt)), http1, list_cache: None, redirects, list_saves, meter, pinned, dispositions, frames,
+		#[cfg(feature = "s3")]
+		upload,
+	})
+}
+
+type BuildClient = Box<dyn Fn(&[(String, std::net::SocketAddr)]) -> Result<http::Client> + Send + Sync>;
+
+/// Addresses of the hosts of a run, looked up once and pinned into a client for --resolve-dns-once
+struct PinnedDns {
+	/// Host names with the port to look them up for
+	hosts: Vec<(String, u16)>,
+	build: BuildClient,
+	current: std::sync::RwLock<(http::Client, Vec<(String, std::net::SocketAddr)>)>,
+}
+
+impl PinnedDns
+{
+	async fn new(hosts: Vec<(String, u16)>, build: BuildClient) -> Result<Self>
+	{
+		let addrs = lookup_hosts(&hosts).await?;
+		let http = build(&addrs)?;
+		Ok(PinnedDns { hosts, build, current: std::sync::RwLock::new((http, addrs)) })
+	}
+
+	fn http(&self) -> http::Client
+	{
+		self.current.read().unwrap().0.clone()
+	}
+
+	/// Look up the hosts again, returning the new addresses if any changed
+	async fn refresh(&self) -> Result<Option<Vec<(String, std::net::SocketAddr)>>>
+	{
+		let addrs = lookup_hosts(&self.hosts).await?;
+		if addrs == self.current.read().unwrap().1 {
+			return Ok(None);
+		}
+		let http = (self.build)(&addrs)?;
+		*self.current.write().unwrap() = (http, addrs.clone());
+		Ok(Some(addrs))
+	}
+}
+
+async fn lookup_hosts(hosts: &[(String, u16)]) -> Result<Vec<(String, std::net::SocketAddr)>>
+{
+	let mut addrs = Vec::new();
+	for (host, port) in hosts {
+		let addr = tokio::net::lookup_host((host.as_str(), *port)).await
+			.with_context(|| format!("failed to look up {}", host))?
+			.next()
+			.with_context(|| format!("no addresses for {}", host))?;
+		addrs.push((host.clone(), addr));
+	}
+	Ok(addrs)
+}
+
+fn http_client(opt: &Opt, redirects: Option<&Arc<Redirects>>) -> Result<http::Client>
+{
+	http_client_builder(opt, redirects).build()
+		.context("failed to build http client")
+}
+
+/// Limit on whole requests, or only on connecting and the response headers with --body-timeout
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+fn http_client_builder(opt: &Opt, redirects: Option<&Arc<Redirects>>) -> http::ClientBuilder
+{
+	let mut builder = http::Client::builder()
+		.connect_timeout(REQUEST_TIMEOUT);
+	// it would also cut off bodies that are slow but still flowing
+	if opt.body_timeout.is_none() {
+		builder = builder.timeout(REQUEST_TIMEOUT);
+	}
+	if opt.fresh_connection {
+		builder = builder.pool_max_idle_per_host(0);
+	}
+	if let Some(redirects) = redirects {
+		let redirects = redirects.clone();
+		builder = builder.redirect(http::redirect::Policy::custom(move |attempt| {
+			// same limit as the default policy
+			if attempt.previous().len() > 10 {
+				return attempt.error("too many redirects");
+			}
+			redirects.push(&attempt.previous()[0], Hop {
+				url: attempt.previous().last().unwrap().to_string(),
+				status: attempt.status().as_u16(),
+			});
+			attempt.follow()
+		}));
+	}
+	builder
+		.tcp_keepalive(opt.tcp_keepalive)
+		.http2_initial_stream_window_size(opt.http2_initial_window)
+		.http2_initial_connection_window_size(opt.http2_connection_window)
+}
+
+/// Whether a request failed in the HTTP/2 layer, e.g. a broken handshake
+fn is_http2_error(err: &http::Error) -> bool
+{
+	// the h2 error types are not exposed, but hyper describes them all alike
+	let mut source: Option<&dyn std::error::Error> = Some(err);
+	while let Some(err) = source {
+		if err.to_string().starts_with("http2 error") {
+			return true;
+		}
+		source = err.source();
+	}
+	false
+}
+
+fn base_url(url: &http::Url) -> http::Url
+{
+	let mut url = url.clone();
+	url.path_segments_mut().unwrap().pop_if_empty();
+	url
+}
+
+/// Base URL that image ids are relative to
+fn image_base(opt: &Opt) -> http::Url
+{
+	base_url(opt.image_base_url.as_ref().unwrap_or(&opt.url))
+}
+
+fn image_url(url_base: &http::Url, img_path: &str) -> http::Url
+{
+	let mut url = url_base.clone();
+	url.path_segments_mut().unwrap().extend(img_path.split('/'));
+	url
+}
+
+/// URL of image `img`, from --url-from-template or below `url_base`
+fn image_location(opt: &Opt, url_base: &http::Url, img: &str) -> Result<http::Url>
+{
+	match &opt.url_from_template {
+		Some(template) => template_url(opt.image_name_regex.as_ref(), template, img),
+		None => Ok(image_url(url_base, &(img.to_owned() + &opt.suffix_full))),
+	}
+}
+
+/// Fill in --url-from-template for `img`; `date` and `time` come from the --image-name-regex groups
+/// or else from the digits of the id, the first eight being the date
+fn template_url(re: Option<&Regex>, template: &str, img: &str) -> Result<http::Url>
+{
+	let (date, time) = match re.and_then(|re| re.captures(img)) {
+		Some(caps) => {
+			let group = |name| caps.name(name).map_or("", |m| m.as_str()).to_owned();
+			(group("date"), group("time"))
+		},
+		None => {
+			let digits: String = img.chars().filter(char::is_ascii_digit).collect();
+			let (date, time) = digits.split_at(digits.len().min(8));
+			(date.to_owned(), time.to_owned())
+		},
+	};
+	let url = template.replace("{id}", img)
+		.replace("{date}", &date)
+		.replace("{time}", &time);
+	http::Url::parse(&url)
+		.with_context(|| format!("invalid image URL {:?} from --url-from-template", url))
+}
+
+/// Sanitized `filename` parameter of a `Content-Disposition` header
+fn disposition_filename(headers: &http::header::HeaderMap) -> Option<String>
+{
+	let value = headers.get(http::header::CONTENT_DISPOSITION)?.to_str().ok()?;
+	let name = value.split(';')
+		.filter_map(|param| param.trim().split_once('='))
+		.find(|(key, _)| key.trim().eq_ignore_ascii_case("filename"))
+		.map(|(_, name)| name.trim().trim_matches('"'))?;
+
+	// only the last path component, so `../` or absolute paths can't leave the archive
+	let name: String = name.rsplit(['/', '\\']).next()?
+		.chars()
+		.filter(|c| !c.is_control())
+		.collect();
+	let name = name.trim_start_matches('.').trim();
+	(!name.is_empty()).then(|| name.to_owned())
+}
+
+/// All headers as a JSON object, joining repeated ones with `, `
+fn record_headers(headers: &http::header::HeaderMap) -> Vec<u8>
+{
+	serde_json::to_vec_pretty(&headers_by_name(headers)).unwrap_or_default()
+}
+
+/// Response headers by name, repeated ones joined with commas
+fn headers_by_name(headers: &http::header::HeaderMap) -> serde_json::Map<String, serde_json::Value>
+{
+	let mut map = serde_json::Map::new();
+	for name in headers.keys() {
+		let values: Vec<_> = headers.get_all(name).iter()
+			.map(|hv| String::from_utf8_lossy(hv.as_bytes()).into_owned())
+			.collect();
+		map.insert(name.to_string(), values.join(", ").into());
+	}
+	map
+}
+
+/// URL of an image download request with the query adjusted by `--strip-query` and `--append-query`
+fn download_url(opt: &Opt, url: &http::Url) -> http::Url
+{
+	let mut url = url.clone();
+	if opt.strip_query {
+		url.set_query(None);
+	}
+	if !opt.append_query.is_empty() {
+		url.query_pairs_mut().extend_pairs(&opt.append_query);
+	}
+	url
+}
+
+/// Local path of an image; only one layout option can be given, see the `layout` group
+fn image_file(opt: &Opt, img_path: &str) -> PathBuf
+{
+	let path = layout_file(opt, img_path);
+	match opt.max_filename_length {
+		Some(max) => shorten_name(&path, max, &opt.suffix_full).unwrap_or(path),
+		None => path,
+	}
+}
+
+fn layout_file(opt: &Opt, img_path: &str) -> PathBuf
+{
+	let dir = image_root(opt);
+	if let Some(buckets) = opt.split_by_size {
+		let img = img_path.strip_suffix(&opt.suffix_full).unwrap_or(img_path);
+		dir.join(bucket(img, buckets)).join(img_path.replace('/', "_"))
+	} else if opt.flatten_names {
+		dir.join(img_path.replace('/', "_"))
+	} else {
+		dir.join(img_path)
+	}
+}
+
+/// Image id of a file at `rel` below the image root, the reverse of `layout_file()`
+fn layout_id(opt: &Opt, rel: &Path) -> String
+{
+	let rel: PathBuf = match opt.split_by_size {
+		Some(_) => rel.iter().skip(1).collect(),
+		None => rel.to_owned(),
+	};
+	let name = rel.to_string_lossy();
+	let img = name.strip_suffix(&opt.suffix_full).unwrap_or(&name);
+	match opt.flatten_names || opt.split_by_size.is_some() {
+		true => img.replace('_', "/"),
+		false => img.to_owned(),
+	}
+}
+
+/// Folder of --split-by-size for image `img`, as many hex digits as the highest of `buckets` needs
+fn bucket(img: &str, buckets: u32) -> String
+{
+	let hash = Sha256::digest(img.as_bytes());
+	let index = u64::from_be_bytes(hash[..8].try_into().unwrap()) % buckets as u64;
+	format!("{:0width$x}", index, width = format!("{:x}", buckets - 1).len())
+}
+
+/// Folder that image ids are relative to
+fn image_root(opt: &Opt) -> PathBuf
+{
+	match opt.output_by_camera {
+		true => opt.download_dir.join(camera_name(&opt.url)),
+		false => opt.download_dir.clone(),
+	}
+}
+
+/// Name of the webcam, the last segment of its URL
+fn camera_name(url: &http::Url) -> String
+{
+	base_url(url).path_segments()
+		.and_then(Iterator::last)
+		.unwrap_or_default()
+		.to_owned()
+}
+
+/// Whether `path` of image `img` got its name cut by --max-filename-length
+fn is_shortened(opt: &Opt, img: &str, path: &Path) -> bool
+{
+	opt.max_filename_length
+		.and_then(|max| shorten_name(&layout_file(opt, &(img.to_owned() + &opt.suffix_full)), max, &opt.suffix_full))
+		.is_some_and(|short| short == path)
+}
+
+/// `path` with its file name cut to `max` bytes, if longer, keeping `suffix` and adding a hash of the full name
+fn shorten_name(path: &Path, max: usize, suffix: &str) -> Option<PathBuf>
+{
+	let name = path.file_name()?.to_str()?;
+	if name.len() <= max {
+		return None;
+	}
+	let keep = if name.ends_with(suffix) { suffix } else { "" };
+	let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+	let mut len = max.saturating_sub(keep.len() + NAME_HASH_LEN + 1);
+	while !name.is_char_boundary(len) {
+		len -= 1;
+	}
+	Some(path.with_file_name(format!("{}~{}{}", &name[..len], &hash[..NAME_HASH_LEN], keep)))
+}
+
+const NAME_HASH_LEN: usize = 8;
+
+/// Remove the images of the download folder whose ids are not in `listed`, if --yes is given
+async fn delete_missing(opt: &Opt, pb: &ProgressBar, listed: &BTreeSet<String>) -> Result<()>
+{
+	let root = image_root(opt);
+	let keep: HashSet<_> = listed.iter()
+		.map(|img| image_file(opt, &(img.clone() + &opt.suffix_full)))
+		.collect();
+	let local = {
+		let (dir, suffix) = (root.clone(), opt.suffix_full.clone());
+		tokio::task::spawn_blocking(move || archive::scan(&dir, &suffix)).await??
+	};
+	let missing: Vec<_> = local.into_iter()
+		.map(|rel| root.join(rel))
+		.filter(|path| !keep.contains(path))
+		.collect();
+	if missing.is_empty() {
+		return Ok(());
+	}
+	if !opt.yes {
+		pb.println(format!("{} local images are no longer listed (use --yes to delete them)", missing.len()));
+		return Ok(());
+	}
+
+	for path in &missing {
+		remove_image(path).await?;
+	}
+	pb.println(format!("Deleted {} images no longer listed", missing.len()));
+	Ok(())
+}
+
+/// Delete an archived image with its sidecars, returning whether there was one
+async fn remove_image(path: &Path) -> Result<bool>
+{
+	match fs::remove_file(path).await {
+		Ok(()) => (),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+		Err(err) => return Err(err)
+			.with_context(|| format!("failed to delete {}", path.display())),
+	}
+	fs::remove_file(path.with_extension("headers.json")).await.ok();
+	fs::remove_file(path.with_extension("redirects.json")).await.ok();
+	fs::remove_file(path.with_extension("meta.json")).await.ok();
+	Ok(true)
+}
+
+/// Replace the symlink `name` in the download folder with one to `target`
+async fn link_latest(opt: &Opt, name: &str, target: &Path) -> Result<()>
+{
+	let link = opt.download_dir.join(name);
+	let tmp = opt.download_dir.join(format!(".{}.tmp", name));
+	// relative, so the archive can be moved as a whole
+	let target = target.strip_prefix(&opt.download_dir).unwrap_or(target);
+
+	fs::remove_file(&tmp).await.ok();
+	fs::symlink(target, &tmp).await
+		.with_context(|| format!("failed to create symlink {}", tmp.display()))?;
+	fs::rename(&tmp, &link).await
+		.with_context(|| format!("failed to move symlink into place at {}", link.display()))
+}
+
+const NAMES_FILE: &str = ".camscrub-names.tsv";
+
+const DISPOSITIONS_FILE: &str = ".camscrub-dispositions.tsv";
+
+/// Paths of the images named by --use-content-disposition, relative to the download folder
+struct Dispositions {
+	path: PathBuf,
+	names: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl Dispositions
+{
+	async fn load(path: &Path) -> Result<Self>
+	{
+		// appended to, so a later line for an id replaces an earlier one
+		let names = match fs::read_to_string(path).await {
+			Ok(data) => data.lines()
+				.filter_map(|line| line.split_once('\t'))
+				.map(|(img, name)| (img.to_owned(), PathBuf::from(name)))
+				.collect(),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+			Err(err) => return Err(err)
+				.with_context(|| format!("failed to read {}", path.display())),
+		};
+		Ok(Dispositions { path: path.to_owned(), names: Mutex::new(names) })
+	}
+
+	fn get(&self, img: &str) -> Option<PathBuf>
+	{
+		self.names.lock().unwrap().get(img).cloned()
+	}
+
+	/// Remember where `img` was saved, adding it to the list if that changed
+	async fn record(&self, img: &str, name: &Path) -> Result<()>
+	{
+		let known = self.names.lock().unwrap().insert(img.to_owned(), name.to_owned());
+		if known.as_deref() == Some(name) {
+			return Ok(());
+		}
+		let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path).await
+			.with_context(|| format!("failed to open {}", self.path.display()))?;
+		file.write_all(format!("{}\t{}\n", img, name.display()).as_bytes()).await
+			.with_context(|| format!("failed to write {}", self.path.display()))?;
+		file.flush().await
+			.with_context(|| format!("failed to write {}", self.path.display()))
+	}
+}
+
+/// Add the ids of images with shortened names to the list in the download folder
+async fn record_names(opt: &Opt, renamed: &BTreeMap<PathBuf, String>) -> Result<()>
+{
+	let path = opt.download_dir.join(NAMES_FILE);
+	let mut names = match fs::read_to_string(&path).await {
+		Ok(data) => data.lines()
+			.filter_map(|line| line.split_once('\t'))
+			.map(|(name, img)| (PathBuf::from(name), img.to_owned()))
+			.collect(),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+		Err(err) => return Err(err)
+			.with_context(|| format!("failed to read {}", path.display())),
+	};
+	for (file, img) in renamed {
+		names.insert(file.strip_prefix(&opt.download_dir).unwrap_or(file).to_owned(), img.clone());
+	}
+
+	let data: String = names.iter()
+		.map(|(name, img)| format!("{}\t{}\n", name.display(), img))
+		.collect();
+	state::write(&path, data).await
+		.with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[derive(Serialize)]
+struct ListRequest {
+	wc: String,
+	thumbs: u32,
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+	thumbs: Vec<String>,
+}
+
+/// Paged access to the `list.php` endpoint of a webcam site
+struct Listing {
+	url: http::Url,
+	client: Client,
+	list_req: ListRequest,
+}
+
+impl Listing
+{
+	fn new(client: &Client, url_base: &http::Url) -> Result<Self>
+	{
+		let webcam = url_base.path_segments()
+			.and_then(Iterator::last)
+			.context("missing webcam at the end of URL")?;
+
+		let url = {
+			let mut url = url_base.clone();
+			url.path_segments_mut().unwrap()
+				.pop()
+				.extend(["include", "list.php"]);
+			url
+		};
+		let list_req = ListRequest { wc: webcam.to_owned(), thumbs: 500 };
+
+		Ok(Listing { url, client: client.clone(), list_req })
+	}
+
+	/// Fetch the page of images older than `img_oldest` (or the newest page if empty)
+	async fn fetch(&self, img_oldest: &str) -> Result<ListResponse>
+	{
+		let cache = self.client.list_cache.as_deref();
+		// new images show up on the newest page
+		if let Some(thumbs) = cache.filter(|_| !img_oldest.is_empty()).and_then(|cache| cache.get(img_oldest)) {
+			return Ok(ListResponse { thumbs });
+		}
+
+		let body = self.fetch_body(img_oldest).await?;
+		if let Some(saves) = &self.client.list_saves {
+			saves.save(&body).await?;
+		}
+		let res: ListResponse = serde_json::from_str(&body)
+			.with_context(|| format!("failed to parse response: {}", snippet(&body)))?;
+		match cache {
+			Some(cache) if img_oldest.is_empty() => cache.insert_newest(res.thumbs.clone()),
+			Some(cache) => cache.insert(img_oldest, res.thumbs.clone()),
+			None => (),
+		}
+		Ok(res)
+	}
+
+	/// Like `fetch()`, but retries failed requests and malformed pages with backoff
+	async fn fetch_page(&self, img_oldest: &str, pb: &ProgressBar) -> Result<ListResponse>
+	{
+		retry(&self.client.retries, pb, "listing", || self.fetch(img_oldest)).await
+	}
+
+	async fn fetch_body(&self, img_oldest: &str) -> Result<String>
+	{
+		// the client has no limit on whole requests with --body-timeout
+		let req = self.client.get(self.url.clone()).await?
+			.timeout(REQUEST_TIMEOUT)
+			.query(&self.list_req)
+			.query(&[("img", img_oldest)]);
+		self.client.send(req)
+			.await.context("failed to send request")?
+			.text()
+			.await.context("failed to read response")
+	}
+}
+
+/// Listing pages by their `img` cursor, kept until they are older than the TTL
+struct ListCache {
+	ttl: time::Duration,
+	pages: Mutex<HashMap<String, (time::Instant, Vec<String>)>>,
+}
+
+impl ListCache
+{
+	fn new(ttl: time::Duration) -> Self
+	{
+		ListCache { ttl, pages: Mutex::default() }
+	}
+
+	fn get(&self, img_oldest: &str) -> Option<Vec<String>>
+	{
+		let pages = self.pages.lock().unwrap();
+		pages.get(img_oldest)
+			.filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+			.map(|(_, thumbs)| thumbs.clone())
+	}
+
+	fn insert(&self, img_oldest: &str, thumbs: Vec<String>)
+	{
+		self.pages.lock().unwrap()
+			.insert(img_oldest.to_owned(), (time::Instant::now(), thumbs));
+	}
+
+	/// Store the newest page, dropping all others if it lists images the cached one doesn't,
+	/// as the older pages start at other images then
+	fn insert_newest(&self, thumbs: Vec<String>)
+	{
+		let mut pages = self.pages.lock().unwrap();
+		let new = match pages.get("") {
+			Some((_, cached)) => thumbs.iter().any(|img| !cached.contains(img)),
+			None => true,
+		};
+		if new {
+			pages.clear();
+		}
+		pages.insert(String::new(), (time::Instant::now(), thumbs));
+	}
+
+	fn clear(&self)
+	{
+		self.pages.lock().unwrap().clear();
+	}
+}
+
+/// Folder of --list-response-save and the number of listing responses saved there by this client
+struct ListSaves {
+	dir: PathBuf,
+	pages: AtomicU64,
+}
+
+impl ListSaves
+{
+	async fn save(&self, body: &str) -> Result<()>
+	{
+		let page = self.pages.fetch_add(1, Ordering::Relaxed) + 1;
+		let path = self.dir.join(format!("{:05}-{}.json", page, Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+		fs::write(&path, body).await
+			.with_context(|| format!("failed to save listing response {}", path.display()))
+	}
+}
+
+/// Retry limits of a run
+struct Retries {
+	/// Retries of a single request
+	limit: u32,
+	/// Retries of a single download by the class of its failure
+	connect: u32,
+	body: u32,
+	/// Retries left for the whole run
+	budget: Option<AtomicU32>,
+}
+
+impl Retries
+{
+	fn new(opt: &Opt) -> Self
+	{
+		Retries {
+			limit: opt.retries,
+			connect: opt.connect_retries,
+			body: opt.body_retries,
+			budget: opt.retry_budget.map(AtomicU32::new),
+		}
+	}
+
+	/// Whether the request may be tried again after `attempt` retries, using up some of the budget
+	fn allow(&self, attempt: u32) -> bool
+	{
+		self.allow_up_to(self.limit, attempt)
+	}
+
+	fn allow_up_to(&self, limit: u32, attempt: u32) -> bool
+	{
+		attempt < limit && self.budget.as_ref().is_none_or(|budget| {
+			budget.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)).is_ok()
+		})
+	}
+}
+
+/// Run `f` until it succeeds, as often as `retries` allows, doubling the delay in between
+async fn retry<T, F, Fut>(retries: &Retries, pb: &ProgressBar, what: &str, mut f: F) -> Result<T>
+where
+	F: FnMut() -> Fut,
+	Fut: std::future::Future<Output = Result<T>>,
+{
+	let mut delay = RETRY_DELAY;
+	let mut attempt = 0;
+	loop {
+		match f().await {
+			Err(err) if retries.allow(attempt) => {
+				pb.println(format!("{} failed, retrying in {}s: {:#}", what, delay.as_secs(), err));
+				time::sleep(delay).await;
+				delay = (delay * 2).min(RETRY_DELAY_MAX);
+				attempt += 1;
+			},
+			res => return res,
+		}
+	}
+}
+
+/// Why a download failed, as far as retrying it is concerned
+#[derive(Clone, Copy)]
+enum Failure {
+	/// No response, so nothing of the image was transferred
+	Connect,
+	/// The response broke off, maybe because of the image itself
+	Body,
+}
+
+impl Failure
+{
+	/// Class of `err`, if retrying could help at all
+	fn of(err: &anyhow::Error) -> Option<Self>
+	{
+		if err.is::<ShortBody>() || err.is::<time::error::Elapsed>() {
+			return Some(Failure::Body);
+		}
+		let err = err.chain().find_map(|err| err.downcast_ref::<http::Error>())?;
+		if err.is_connect() || err.is_timeout() {
+			Some(Failure::Connect)
+		} else if err.is_body() || err.is_decode() {
+			Some(Failure::Body)
+		} else {
+			None
+		}
+	}
+}
+
+/// Whether `err` is a 404 answer to a download
+fn is_not_found(err: &anyhow::Error) -> bool
+{
+	err.chain()
+		.find_map(|err| err.downcast_ref::<http::Error>())
+		.and_then(http::Error::status) == Some(http::StatusCode::NOT_FOUND)
+}
+
+/// Like `download()`, but retries connection and body failures as often as --connect-retries and --body-retries allow
+async fn download_retrying(opt: &Opt, client: &Client, pb: &ProgressBar, img: &str, url: &http::Url, path: &Path, dirs: &DirCache) -> Result<Download>
+{
+	retrying(opt, client, pb, &format!("download of {}", img), || download(opt, client, img, url, path, dirs)).await
+}
+
+/// Run `f` again after connection and body failures, as `download_retrying()` does
+async fn retrying<T, F, Fut>(opt: &Opt, client: &Client, pb: &ProgressBar, what: &str, mut f: F) -> Result<T>
+where
+	F: FnMut() -> Fut,
+	Fut: std::future::Future<Output = Result<T>>,
+{
+	let retries = &client.retries;
+	let mut delay = RETRY_DELAY;
+	let (mut connects, mut bodies) = (0, 0);
+	loop {
+		let err = match f().await {
+			Ok(res) => return Ok(res),
+			Err(err) => err,
+		};
+		let (attempt, limit) = match Failure::of(&err) {
+			Some(Failure::Connect) => (&mut connects, retries.connect),
+			Some(Failure::Body) => (&mut bodies, retries.body),
+			None => return Err(err),
+		};
+		if !retries.allow_up_to(limit, *attempt) {
+			return Err(err);
+		}
+		if !opt.summary_only {
+			pb.println(format!("{} failed, retrying in {}s: {}", what, delay.as_secs(), err));
+		}
+		// the host may have moved
+		if let (Some(pinned), Some(Failure::Connect)) = (&client.pinned, Failure::of(&err)) {
+			match pinned.refresh().await {
+				Ok(Some(addrs)) => for (host, addr) in addrs {
+					pb.println(format!("{} now resolves to {}", host, addr.ip()));
+				},
+				Ok(None) => (),
+				Err(err) => pb.println(format!("{:#}", err)),
+			}
+		}
+		time::sleep(delay).await;
+		delay = (delay * 2).min(RETRY_DELAY_MAX);
+		*attempt += 1;
+	}
+}
+
+const RETRY_DELAY: time::Duration = time::Duration::from_secs(1);
+const RETRY_DELAY_MAX: time::Duration = time::Duration::from_secs(60);
+
+/// Redirects followed so far by the URL each chain started at
+#[derive(Default)]
+struct Redirects(Mutex<HashMap<http::Url, Vec<Hop>>>);
+
+impl Redirects
+{
+	fn push(&self, start: &http::Url, hop: Hop)
+	{
+		self.0.lock().unwrap().entry(start.clone()).or_default().push(hop);
+	}
+
+	/// Redirects of the last request to `start`, forgetting them
+	fn take(&self, start: &http::Url) -> Vec<Hop>
+	{
+		self.0.lock().unwrap().remove(start).unwrap_or_default()
+	}
+}
+
+/// Response of a redirect chain
+#[derive(Serialize)]
+struct Hop {
+	url: String,
+	status: u16,
+}
+
+/// Provenance of a downloaded image, for --per-image-metadata-json
+#[derive(Serialize)]
+struct ImageMeta {
+	img: String,
+	/// From the image id, if it has a time
+	captured: Option<DateTime<Utc>>,
+	/// After any redirects
+	url: String,
+	size: u64,
+	sha256: String,
+	status: u16,
+	headers: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Beginning of a response body for error messages
+fn snippet(body: &str) -> String
+{
+	let mut snippet: String = body.chars().take(120).collect();
+	if snippet.len() < body.len() {
+		snippet.push_str("...");
+	}
+	snippet
+}
+
+/// Capture time encoded in the digits of an image id, e.g. `2022/05/01/1200` in local time
+///
+/// With an image name regex only the digits of its `date` and `time` groups are used.
+fn image_time(re: Option<&Regex>, tz: Option<Tz>, img: &str) -> Option<DateTime<Utc>>
+{
+	let text = match re {
+		Some(re) => {
+			let caps = re.captures(img)?;
+			["date", "time"].iter()
+				.filter_map(|name| caps.name(name))
+				.map(|m| m.as_str())
+				.collect()
+		},
+		None => img.to_owned(),
+	};
+	let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+	let time = match digits.len() {
+		12 => NaiveDateTime::parse_from_str(&digits, "%Y%m%d%H%M"),
+		14 => NaiveDateTime::parse_from_str(&digits, "%Y%m%d%H%M%S"),
+		_ => return None,
+	}.ok()?;
+
+	from_wall_clock(tz, time)
+}
+
+/// Wall-clock time of `time` in the --timezone, or the local zone
+fn wall_clock(tz: Option<Tz>, time: DateTime<Utc>) -> NaiveDateTime
+{
+	match tz {
+		Some(tz) => time.with_timezone(&tz).naive_local(),
+		None => time.with_timezone(&Local).naive_local(),
+	}
+}
+
+/// Inverse of `wall_clock()`, the earlier one of ambiguous times
+fn from_wall_clock(tz: Option<Tz>, time: NaiveDateTime) -> Option<DateTime<Utc>>
+{
+	match tz {
+		Some(tz) => tz.from_local_datetime(&time).earliest().map(|dt| dt.with_timezone(&Utc)),
+		None => Local.from_local_datetime(&time).earliest().map(|dt| dt.with_timezone(&Utc)),
+	}
+}
+
+/// Turn listed thumbnails into image ids, detecting the thumbnail suffix if still unknown
+fn strip_thumbs(suffix_thumb: &mut Option<String>, thumbs: Vec<String>) -> Vec<String>
+{
+	if suffix_thumb.is_none() {
+		*suffix_thumb = thumbs.first()
+			.and_then(|thumb| detect_suffix(thumb))
+			.map(str::to_owned);
+	}
+	let suffix = suffix_thumb.as_deref().unwrap_or_default();
+
+	thumbs.into_iter()
+		.map(|img| img.strip_suffix(suffix)
+			.map(|s| s.to_owned()).unwrap_or(img))
+		.collect()
+}
+
+/// `suffix` with its extension (if any) replaced by `ext`, e.g. `_hu.jpg` to `_hu.png`
+fn replace_extension(suffix: &str, ext: &str) -> String
+{
+	let stem = suffix.rfind('.').map_or(suffix, |pos| &suffix[..pos]);
+	format!("{}.{}", stem, ext)
+}
+
+/// Guess the vendor suffix of a listed image, e.g. `_la.jpg` in `2022/05/01/1200_la.jpg`
+fn detect_suffix(img: &str) -> Option<&str>
+{
+	let name = img.rsplit('/').next()?;
+	name.rfind('_').map(|pos| &name[pos..])
+}
+
+/// Directories already created by any of the workers
+#[derive(Default)]
+struct DirCache(Mutex<HashSet<PathBuf>>);
+
+impl DirCache
+{
+	async fn create_dir_all(&self, dir: &Path) -> Result<()>
+	{
+		if self.0.lock().unwrap().contains(dir) {
+			return Ok(());
+		}
+
+		fs::create_dir_all(dir).await
+			.with_context(|| format!("failed to create directory {}", dir.display()))?;
+		self.0.lock().unwrap().insert(dir.to_owned());
+		Ok(())
+	}
+}
+
+enum Status {
+	Downloaded,
+	Exists,
+	/// Not saved, as it is the same as the frame before
+	Identical,
+}
+
+struct Download {
+	status: Status,
+	/// Where the image is in the archive
+	path: PathBuf,
+	/// Bytes written
+	size: u64,
+	/// Missing if the server was not asked
+	headers: Option<CacheHeaders>,
+	/// Hex SHA-256 of the image file, only computed by the options that need it
+	sha256: Option<String>,
+}
+
+/// Caching related headers of a download response
+struct CacheHeaders {
+	date: DateTime<Utc>,
+	cache_control: bool,
+	expires: bool,
+	last_modified: Option<DateTime<Utc>>,
+}
+
+impl CacheHeaders
+{
+	fn from_response(resp: &http::Response) -> Self
+	{
+		let headers = resp.headers();
+		CacheHeaders {
+			date: Utc::now(),
+			cache_control: headers.contains_key(http::header::CACHE_CONTROL),
+			expires: headers.contains_key(http::header::EXPIRES),
+			last_modified: last_modified(resp).ok(),
+		}
+	}
+}
+
+fn last_modified(resp: &http::Response) -> Result<DateTime<Utc>>
+{
+	resp.headers().get("Last-Modified")
+		.context("missing Last-Modified header")
+		.and_then(|hv|  hv.to_str().context("invalid header value"))
+		.and_then(|value| DateTime::parse_from_rfc2822(value)
+				.map(|dt| dt.with_timezone(&Utc))
+				.context("invalid modify time"))
+}
+
+/// Send a download request, within REQUEST_TIMEOUT also when --body-timeout leaves that to the caller
+async fn send_download(opt: &Opt, client: &Client, req: http::RequestBuilder) -> Result<http::Response>
+{
+	match opt.body_timeout {
+		Some(_) => time::timeout(REQUEST_TIMEOUT, client.send(req)).await
+			.unwrap_or_else(|elapsed| Err(anyhow::Error::new(elapsed)
+				.context(format!("no response within {}", humantime::format_duration(REQUEST_TIMEOUT))))),
+		None => client.send(req).await,
+	}
+}
+
+/// Next chunk of a download body, failing once --body-timeout passes without one
+async fn next_chunk<S, T>(opt: &Opt, stream: &mut S) -> Option<Result<T>>
+where
+	S: futures_util::Stream<Item = Result<T, http::Error>> + Unpin,
+{
+	let next = async { stream.next().await.map(|chunk| chunk.map_err(anyhow::Error::from)) };
+	match opt.body_timeout {
+		Some(idle) => time::timeout(idle, next).await
+			.unwrap_or_else(|elapsed| Some(Err(anyhow::Error::new(elapsed)
+				.context(format!("no data received for {}", humantime::format_duration(idle)))))),
+		None => next.await,
+	}
+}
+
+/// Size of the image at `url`, received the way `download()` receives it but not saved
+async fn receive(opt: &Opt, client: &Client, url: &http::Url) -> Result<u64>
+{
+	let url = download_url(opt, url);
+	let resp = send_download(opt, client, client.get(url.clone()).await?).await
+		.context("failed to send download request")?
+		.error_for_status()
+		.with_context(|| format!("failed to download {}", &url))?;
+	let mut size = 0;
+	let mut stream = resp.bytes_stream();
+	while let Some(chunk) = next_chunk(opt, &mut stream).await {
+		let len = chunk?.len() as u64;
+		size += len;
+		client.meter.record(len);
+	}
+	Ok(size)
+}
+
+async fn download(opt: &Opt, client: &Client, img: &str, url: &http::Url, path: &Path, dirs: &DirCache) -> Result<Download>
+{
+	if opt.simulate_error_rate > 0.0 && rand::random::<f64>() < opt.simulate_error_rate {
+		anyhow::bail!("simulated error");
+	}
+
+	// a name from Content-Disposition is only known from an earlier download
+	let id_path = path;
+	let path = &client.dispositions.as_ref()
+		.and_then(|dispositions| dispositions.get(img))
+		.map_or_else(|| id_path.to_owned(), |rel| opt.download_dir.join(rel));
+	let mut md = fs::metadata(path).await;
+	// capture times are older than any Last-Modified, so the server can't tell us about updates
+	let source = mtime_source(opt);
+	// a corrupt image of --replace-corrupt is fetched again no matter its file time
+	if opt.replace_corrupt {
+		md = Err(std::io::ErrorKind::NotFound.into());
+	}
+	if matches!(source, MtimeSource::Id | MtimeSource::Min) && md.is_ok() && opt.pretend_modified_since.is_none() {
+		let sha256 = existing_sha256(opt, path).await?;
+		return Ok(Download { status: Status::Exists, path: path.to_owned(), size: 0, headers: None, sha256 });
+	}
+
+	let skew = chrono::Duration::seconds(opt.max_clock_skew.map_or(0, |skew| skew.as_secs() as i64));
+	let local = md.as_ref().ok().and_then(|md| md.modified().ok())
+		.map(|mtime| DateTime::<Utc>::from(mtime) + skew);
+	let mtime = opt.pretend_modified_since
+		.or(local)
+		.unwrap_or_else(|| Utc.timestamp(0, 0));
+
+	let url = &download_url(opt, url);
+	let req = client.get(url.clone()).await?
+		.header("If-Modified-Since", mtime.to_rfc2822());
+	if let Some(redirects) = &client.redirects {
+		// left over by a failed attempt
+		redirects.take(url);
+	}
+	let resp = send_download(opt, client, req).await
+		.context("failed to send download request")?
+		.error_for_status()
+		.with_context(|| format!("failed to download {}", &url))?;
+	let mut redirects = client.redirects.as_ref()
+		.map(|redirects| redirects.take(url))
+		.unwrap_or_default();
+	if !redirects.is_empty() {
+		redirects.push(Hop { url: resp.url().to_string(), status: resp.status().as_u16() });
+	}
+
+	let headers = CacheHeaders::from_response(&resp);
+	if resp.status() == http::StatusCode::NOT_MODIFIED {
+		let sha256 = existing_sha256(opt, path).await?;
+		return Ok(Download { status: Status::Exists, path: path.to_owned(), size: 0, headers: Some(headers), sha256 });
+	}
+
+	// for servers that ignore If-Modified-Since
+	if let (Some(_), None, Some(local), Some(last_modified)) = (opt.max_clock_skew, opt.pretend_modified_since, local, headers.last_modified) {
+		if last_modified <= local {
+			let sha256 = existing_sha256(opt, path).await?;
+			return Ok(Download { status: Status::Exists, path: path.to_owned(), size: 0, headers: Some(headers), sha256 });
+		}
+	}
+
+	if opt.require_last_modified && headers.last_modified.is_none() {
+		return Err(Untimed.into());
+	}
+
+	if opt.format_check {
+		let content_type = resp.headers().get(http::header::CONTENT_TYPE)
+			.and_then(|hv| hv.to_str().ok())
+			.unwrap_or_default();
+		let mime = content_type.split(';').next().unwrap_or_default().trim();
+		anyhow::ensure!(opt.content_types.iter().any(|ct| ct.eq_ignore_ascii_case(mime)),
+			"unexpected content type {:?}", content_type);
+	}
+
+	// the id keeps images apart that the server gives the same name, like `snapshot.jpg`
+	let path = &match opt.use_content_disposition.then(|| disposition_filename(resp.headers())).flatten() {
+		Some(name) => {
+			let file = id_path.file_name().unwrap_or_default().to_string_lossy();
+			id_path.with_file_name(format!("{}_{}", file.strip_suffix(opt.suffix_full.as_str()).unwrap_or(&file), name))
+		},
+		None => id_path.to_owned(),
+	};
+	let archive_path = path.clone();
+
+	let mtime = file_mtime(source, headers.last_modified, image_time(opt.image_name_regex.as_ref(), opt.timezone, img));
+
+	let path = &match staging_dir(opt) {
+		Some(staging) => staging.join(path.strip_prefix(&opt.download_dir).unwrap_or(path)),
+		None => path.to_owned(),
+	};
+	dirs.create_dir_all(path.parent().unwrap()).await?;
+
+	let header_map = opt.record_headers.then(|| record_headers(resp.headers()));
+	#[cfg(feature = "s3")]
+	let (source_url, content_type) = (resp.url().to_string(), resp.headers().get(http::header::CONTENT_TYPE)
+		.and_then(|hv| hv.to_str().ok())
+		.unwrap_or("application/octet-stream")
+		.to_owned());
+	let mut meta = opt.per_image_metadata_json.then(|| ImageMeta {
+		img: img.to_owned(),
+		captured: image_time(opt.image_name_regex.as_ref(), opt.timezone, img),
+		url: resp.url().to_string(),
+		size: 0,
+		sha256: String::new(),
+		status: resp.status().as_u16(),
+		headers: headers_by_name(resp.headers()),
+	});
+
+	// a crash must not leave a truncated image that looks complete
+	let part = part_file(path);
+	let mut file = fs::File::create(&part).await
+		.with_context(|| format!("failed to create image file {}", part.display()))?;
+	// also when cancelled, which drops this future at any await
+	let mut part_guard = PartGuard(Some(&part));
+
+	let expected = resp.content_length();
+	if let Some(len) = expected {
+		file.set_len(len).await.ok();
+	}
+
+	let mut size = 0;
+	let mut hasher = (opt.checksum_manifest.is_some() || opt.dedup_across_runs || opt.skip_identical_consecutive || opt.per_image_metadata_json).then(Sha256::new);
+	// network chunks are often only a few KiB, so collect them to save on write calls
+	let mut buf = Vec::with_capacity(WRITE_BUFFER);
+	let mut stream = resp.bytes_stream();
+	loop {
+		let chunk = match next_chunk(opt, &mut stream).await {
+			Some(Ok(chunk)) => chunk,
+			// usually a connection dropped before Content-Length was reached
+			Some(Err(err)) => return Err(err),
+			None => break,
+		};
+		size += chunk.len() as u64;
+		client.meter.record(chunk.len() as u64);
+		if let Some(hasher) = &mut hasher {
+			hasher.update(&chunk);
+		}
+		buf.extend_from_slice(&chunk);
+		if buf.len() >= WRITE_BUFFER {
+			file.write_all(&buf).await
+				.context("failed to write")?;
+			buf.clear();
+		}
+	}
+	file.write_all(&buf).await
+		.context("failed to write")?;
+	file.flush().await.context("failed to flush")?;
+	// the file was sized to the expected length above, so a short body would go unnoticed
+	if let Some(expected) = expected.filter(|&len| len != size) {
+		return Err(ShortBody { expected, received: size }.into());
+	}
+	if opt.magic_check || opt.decode_verify {
+		let (checked, decode) = (part.clone(), opt.decode_verify);
+		let check = tokio::task::spawn_blocking(move || archive::check_jpeg(&checked, decode)).await??;
+		if check != archive::JpegCheck::Valid {
+			anyhow::bail!("downloaded image is {}", format!("{:?}", check).to_lowercase());
+		}
+	}
+	if opt.fsync {
+		file.sync_all().await.context("failed to sync")?;
+	}
+
+	let sha256 = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+	// the part file goes with the guard
+	if let (Some(frames), Some(sha256)) = (&client.frames, &sha256) {
+		if let Some(before) = frames.repeats(img, sha256).await? {
+			frames.skip(img, &before).await?;
+			return Ok(Download { status: Status::Identical, path: archive_path, size, headers: Some(headers), sha256: Some(sha256.clone()) });
+		}
+	}
+
+	if let Some(header_map) = header_map {
+		let sidecar = path.with_extension("headers.json");
+		fs::write(&sidecar, header_map).await
+			.with_context(|| format!("failed to write headers to {}", sidecar.display()))?;
+	}
+	if !redirects.is_empty() {
+		let sidecar = path.with_extension("redirects.json");
+		fs::write(&sidecar, serde_json::to_vec_pretty(&redirects)?).await
+			.with_context(|| format!("failed to write redirects to {}", sidecar.display()))?;
+	}
+
+	// before the image is in place, so a failed upload is retried by the next run
+	#[cfg(feature = "s3")]
+	if let Some(upload) = &client.upload {
+		let key = archive_path.strip_prefix(&opt.download_dir).unwrap_or(&archive_path);
+		let mut meta = vec![("source-url", source_url)];
+		if let Some(time) = image_time(opt.image_name_regex.as_ref(), opt.timezone, img) {
+			meta.push(("capture-time", time.to_rfc3339()));
+		}
+		upload.upload(&part, &key.to_string_lossy(), &content_type, &meta).await?;
+	}
+
+	// set modification date from server
+	let tv = TimeVal::milliseconds(mtime.timestamp_millis());
+	nix::sys::stat::utimes(&part, &tv, &tv).ok();
+	fs::rename(&part, path).await
+		.with_context(|| format!("failed to move {} into place", part.display()))?;
+	part_guard.0 = None;
+	if let Some(dispositions) = &client.dispositions {
+		dispositions.record(img, archive_path.strip_prefix(&opt.download_dir).unwrap_or(&archive_path)).await?;
+	}
+
+	if let Some(meta) = &mut meta {
+		meta.size = size;
+		meta.sha256 = sha256.clone().unwrap_or_default();
+		let sidecar = path.with_extension("meta.json");
+		fs::write(&sidecar, serde_json::to_vec_pretty(meta)?).await
+			.with_context(|| format!("failed to write metadata to {}", sidecar.display()))?;
+	}
+	Ok(Download { status: Status::Downloaded, path: archive_path, size, headers: Some(headers), sha256 })
+}
+
+const WRITE_BUFFER: usize = 256 * 1024;
+
+/// Download failure of --require-last-modified, counted apart from the others
+#[derive(Debug)]
+struct Untimed;
+
+impl std::fmt::Display for Untimed
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		f.write_str("missing or invalid Last-Modified header")
+	}
+}
+
+impl std::error::Error for Untimed {}
+
+/// Failed refresh of the OAuth2 token, which ends the run instead of failing one image after another
+#[derive(Debug, Clone)]
+struct TokenRefresh(String);
+
+impl std::fmt::Display for TokenRefresh
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "token refresh failed: {}", self.0)
+	}
+}
+
+impl std::error::Error for TokenRefresh {}
+
+/// Download whose body did not match its Content-Length
+#[derive(Debug)]
+struct ShortBody {
+	expected: u64,
+	received: u64,
+}
+
+impl std::fmt::Display for ShortBody
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "received {} of {} bytes announced by Content-Length", self.received, self.expected)
+	}
+}
+
+impl std::error::Error for ShortBody {}
+
+/// Listing without any images, as for a wrong webcam name or an offline camera, counted by --exit-on-empty-listing
+#[derive(Debug)]
+struct EmptyListing;
+
+impl std::fmt::Display for EmptyListing
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "no images listed")
+	}
+}
+
+impl std::error::Error for EmptyListing {}
+
+/// End of --watch by --exit-on-empty-listing
+#[derive(Debug)]
+struct CameraDown {
+	passes: u32,
+}
+
+impl std::fmt::Display for CameraDown
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "no images listed for {} passes in a row, the camera seems to be down", self.passes)
+	}
+}
+
+impl std::error::Error for CameraDown {}
+
+/// Exit code for --exit-on-empty-listing, apart from the 1 of other errors
+const EXIT_CAMERA_DOWN: i32 = 3;
+
+/// No image downloaded with --fail-on-zero-new
+#[derive(Debug)]
+struct NothingNew;
+
+impl std::fmt::Display for NothingNew
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "no new images downloaded")
+	}
+}
+
+impl std::error::Error for NothingNew {}
+
+/// Exit code for --fail-on-zero-new
+const EXIT_NOTHING_NEW: i32 = 4;
+
+const PART_SUFFIX: &str = ".part";
+
+/// Temporary file an image is written to before it is complete
+fn part_file(path: &Path) -> PathBuf
+{
+	let mut name = path.file_name().unwrap_or_default().to_owned();
+	name.push(PART_SUFFIX);
+	path.with_file_name(name)
+}
+
+/// Removes the part file of a download that did not complete, unless emptied
+struct PartGuard<'a>(Option<&'a Path>);
+
+impl Drop for PartGuard<'_>
+{
+	fn drop(&mut self)
+	{
+		if let Some(part) = self.0 {
+			std::fs::remove_file(part).ok();
+		}
+	}
+}
+
+/// Remove partial images left behind by an interrupted run, returning how many there were
+async fn purge_partials(opt: &Opt) -> Result<usize>
+{
+	let mut count = 0;
+	// the scan skips hidden folders, so the default staging directory needs its own
+	for dir in std::iter::once(opt.download_dir.clone()).chain(staging_dir(opt)) {
+		let parts = tokio::task::spawn_blocking({
+			let dir = dir.clone();
+			move || archive::scan(&dir, PART_SUFFIX)
+		}).await??;
+		for part in &parts {
+			let path = dir.join(part);
+			fs::remove_file(&path).await
+				.with_context(|| format!("failed to remove partial image {}", path.display()))?;
+		}
+		count += parts.len();
+	}
+	Ok(count)
+}
+
+fn mtime_source(opt: &Opt) -> MtimeSource
+{
+	match opt.mtime_source {
+		Some(source) => source,
+		None if opt.stabilize_mtime => MtimeSource::Id,
+		None => MtimeSource::Header,
+	}
+}
+
+/// Modification time for a downloaded image, falling back to the other source and then to now
+fn file_mtime(source: MtimeSource, header: Option<DateTime<Utc>>, id: Option<DateTime<Utc>>) -> DateTime<Utc>
+{
+	let time = match (source, header, id) {
+		(MtimeSource::Min, Some(header), Some(id)) => Some(header.min(id)),
+		(MtimeSource::Max, Some(header), Some(id)) => Some(header.max(id)),
+		(MtimeSource::Id, _, Some(id)) => Some(id),
+		_ => header.or(id),
+	};
+	time.unwrap_or_else(Utc::now)
+}
+
+/// Hash of an image already in the archive, if --checksum-manifest wants it
+async fn existing_sha256(opt: &Opt, path: &Path) -> Result<Option<String>>
+{
+	if opt.checksum_manifest.is_none() {
+		return Ok(None);
+	}
+	let data = fs::read(path).await
+		.with_context(|| format!("failed to read image file {}", path.display()))?;
+	Ok(Some(format!("{:x}", Sha256::digest(&data))))
+}
+
+/// Write checksums in `sha256sum` format, with paths relative to the manifest where possible;
+/// those of an earlier manifest are kept while their files are, as a run may not see every image
+async fn write_manifest(manifest: &Path, checksums: &BTreeMap<PathBuf, String>) -> Result<()>
+{
+	let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+	let mut lines = BTreeMap::new();
+	match fs::read_to_string(manifest).await {
+		Ok(data) => for (sha256, name) in data.lines().filter_map(|line| line.split_once("  ")) {
+			if fs::metadata(base.join(name)).await.is_ok() {
+				lines.insert(name.to_owned(), sha256.to_owned());
+			}
+		},
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+		Err(err) => return Err(err)
+			.with_context(|| format!("failed to read checksum manifest {}", manifest.display())),
+	}
+	for (path, sha256) in checksums {
+		let path = path.strip_prefix(base).unwrap_or(path);
+		lines.insert(path.display().to_string(), sha256.clone());
+	}
+
+	let mut out = String::new();
+	for (name, sha256) in lines {
+		out += &format!("{}  {}\n", sha256, name);
+	}
+	state::write(manifest, out).await
+		.with_context(|| format!("failed to write checksum manifest {}", manifest.display()))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn at(hour: u32) -> DateTime<Utc>
+	{
+		Utc.ymd(2022, 5, 1).and_hms(hour, 0, 0)
+	}
+
+	fn opt(args: &[&str]) -> Opt
+	{
+		Opt::try_parse_from(std::iter::once("camscrub").chain(args.iter().copied())).unwrap()
+	}
+
+	#[tokio::test]
+	async fn manifest_keeps_earlier_checksums()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let manifest = dir.path().join("SHA256SUMS");
+		for name in ["a.jpg", "b.jpg", "c.jpg"] {
+			fs::write(dir.path().join(name), name).await.unwrap();
+		}
+		let sums = |names: &[(&str, &str)]| names.iter()
+			.map(|(name, sha256)| (dir.path().join(name), sha256.to_string()))
+			.collect::<BTreeMap<_, _>>();
+
+		write_manifest(&manifest, &sums(&[("a.jpg", "1"), ("b.jpg", "2"), ("gone.jpg", "3")])).await.unwrap();
+		fs::remove_file(dir.path().join("a.jpg")).await.unwrap();
+		// a later pass that only saw some of the images
+		write_manifest(&manifest, &sums(&[("b.jpg", "4"), ("c.jpg", "5")])).await.unwrap();
+		assert_eq!(fs::read_to_string(&manifest).await.unwrap(), "4  b.jpg\n5  c.jpg\n");
+	}
+
+	#[test]
+	fn new_images_drop_cached_pages()
+	{
+		let imgs = |ids: &[&str]| ids.iter().map(|&id| id.to_owned()).collect::<Vec<_>>();
+		let cache = ListCache::new(time::Duration::from_secs(60));
+		cache.insert_newest(imgs(&["b", "c"]));
+		cache.insert("b", imgs(&["a"]));
+		// the same images, or fewer of them
+		cache.insert_newest(imgs(&["c"]));
+		assert_eq!(cache.get("b"), Some(imgs(&["a"])));
+		cache.insert_newest(imgs(&["c", "d"]));
+		assert_eq!(cache.get("b"), None);
+		assert_eq!(cache.get(""), Some(imgs(&["c", "d"])));
+	}
+
+	fn ids(ids: &[&str]) -> Vec<String>
+	{
+		ids.iter().map(|&id| id.to_owned()).collect()
+	}
+
+	#[test]
+	fn downsample_keeps_the_earliest_image_across_pages()
+	{
+		let mut downsample = Downsample::new(time::Duration::from_secs(3600), Some(chrono_tz::UTC));
+		// the oldest bucket of a page may go on in the next, older page
+		let newest = downsample.page(None, ids(&["2022/05/01/1130", "2022/05/01/1200", "2022/05/01/1210", "snapshot"]), false);
+		assert_eq!(newest, ids(&["snapshot", "2022/05/01/1200"]));
+		let older = downsample.page(None, ids(&["2022/05/01/1100", "2022/05/01/1120"]), false);
+		assert!(older.is_empty());
+		let oldest = downsample.page(None, ids(&["2022/05/01/1000"]), true);
+		assert_eq!(oldest, ids(&["2022/05/01/1100", "2022/05/01/1000"]));
+	}
+
+	#[test]
+	fn downsample_buckets_follow_the_timezone()
+	{
+		// the same UTC day, but two days in New York
+		let mut downsample = Downsample::new(time::Duration::from_secs(86400), Some(chrono_tz::America::New_York));
+		let imgs = downsample.page(None, ids(&["2022/05/01/2330", "2022/05/02/0010", "2022/05/02/0900"]), true);
+		assert_eq!(imgs, ids(&["2022/05/02/0010", "2022/05/01/2330"]));
+	}
+
+	#[test]
+	fn buckets_are_stable_and_padded()
+	{
+		assert_eq!(bucket("2022/05/01/1200", 16), "2");
+		assert_eq!(bucket("2022/05/01/1200", 16), bucket("2022/05/01/1200", 16));
+		assert_eq!(bucket("2022/05/01/1200", 1), "0");
+		for (buckets, width) in [(16, 1), (17, 2), (256, 2), (257, 3)] {
+			for img in ["a", "b", "c", "2022/05/01/1200"] {
+				let name = bucket(img, buckets);
+				assert_eq!(name.len(), width);
+				assert!(u32::from_str_radix(&name, 16).unwrap() < buckets);
+			}
+		}
+	}
+
+	#[test]
+	fn layout_id_reverses_layout_file()
+	{
+		for args in [&[][..], &["--flatten-names"], &["--split-by-size", "16"]] {
+			let mut all = vec!["archive", "http://cam.example/webcam/Cam/"];
+			all.extend(args);
+			let opt = opt(&all);
+			let file = layout_file(&opt, "2022/05/01/1200_hu.jpg");
+			let rel = file.strip_prefix("archive").unwrap();
+			assert_eq!(layout_id(&opt, rel), "2022/05/01/1200", "{:?}", args);
+		}
+	}
+
+	#[test]
+	fn template_url_fills_in_placeholders()
+	{
+		let url = template_url(None, "http://cam.example/{date}/{time}/{id}.jpg", "2022/05/01/1200").unwrap();
+		assert_eq!(url.as_str(), "http://cam.example/20220501/1200/2022/05/01/1200.jpg");
+		let re = Regex::new(r"(?P<date>\d{8})_(?P<time>\d{4})").unwrap();
+		let url = template_url(Some(&re), "http://cam.example/{date}T{time}?id={id}", "cam1_20220501_1200").unwrap();
+		assert_eq!(url.as_str(), "http://cam.example/20220501T1200?id=cam1_20220501_1200");
+		// the id is not escaped beyond what makes a valid URL
+		let url = template_url(None, "http://cam.example/{id}.jpg", "a b").unwrap();
+		assert_eq!(url.as_str(), "http://cam.example/a%20b.jpg");
+		assert!(template_url(None, "{id}.jpg", "a").is_err());
+	}
+
+	#[test]
+	fn shorten_name_keeps_suffix_and_length()
+	{
+		let dir = Path::new("archive");
+		assert_eq!(shorten_name(&dir.join("short_hu.jpg"), 12, "_hu.jpg"), None);
+		let long = "a".repeat(40) + "_hu.jpg";
+		let short = shorten_name(&dir.join(&long), 30, "_hu.jpg").unwrap();
+		let name = short.file_name().unwrap().to_str().unwrap();
+		assert_eq!((name.len(), short.parent()), (30, Some(dir)));
+		assert!(name.starts_with(&"a".repeat(14)) && name.ends_with("_hu.jpg"), "{}", name);
+		assert_eq!(name.split_once('~').unwrap().1.len(), NAME_HASH_LEN + "_hu.jpg".len());
+		// names that only differ past the cut stay apart
+		let other = "a".repeat(39) + "b_hu.jpg";
+		assert_ne!(shorten_name(&dir.join(other), 30, "_hu.jpg"), Some(short));
+		// no cut within a character
+		let name = shorten_name(&dir.join("ä".repeat(20) + "_hu.jpg"), 30, "_hu.jpg").unwrap();
+		assert!(name.file_name().unwrap().len() <= 30);
+		// without the suffix, only the hash is kept at the end
+		let name = shorten_name(&dir.join("a".repeat(40) + ".png"), 20, "_hu.jpg").unwrap();
+		let name = name.file_name().unwrap().to_str().unwrap();
+		assert_eq!(name.split_once('~').map(|(cut, hash)| (cut.len(), hash.len())), Some((11, NAME_HASH_LEN)));
+	}
+
+	#[tokio::test]
+	async fn validate_only_decodes_jpegs()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::write(dir.path().join("a_hu.png"), b"\x89PNG").unwrap();
+		std::fs::write(dir.path().join("b_hu.jpg"), b"\x89PNG").unwrap();
+		let args = |suffix| opt(&[dir.path().to_str().unwrap(), "http://cam.example/webcam/Cam/", "--validate-only", "--suffix-full", suffix]);
+		validate(args("_hu.png")).await.unwrap();
+		assert!(validate(args("_hu.jpg")).await.is_err());
+		std::fs::write(dir.path().join("c_hu.png"), b"").unwrap();
+		assert!(validate(args("_hu.png")).await.is_err());
+	}
+
+	#[test]
+	fn probe_then_confirm_needs_the_listing()
+	{
+		opt(&["--probe-then-confirm", "."]);
+		for feed in [&["--from-stdin"][..], &["--input-csv", "ids.csv"], &["--replace-corrupt"]] {
+			let args = std::iter::once("camscrub").chain(["--probe-then-confirm", "."]).chain(feed.iter().copied());
+			assert!(Opt::try_parse_from(args).is_err(), "{:?}", feed);
+		}
+	}
+
+	#[test]
+	fn mtime_source_defaults_to_header()
+	{
+		assert_eq!(mtime_source(&opt(&[])), MtimeSource::Header);
+		assert_eq!(mtime_source(&opt(&["--stabilize-mtime"])), MtimeSource::Id);
+		assert_eq!(mtime_source(&opt(&["--stabilize-mtime", "--mtime-source", "max"])), MtimeSource::Max);
+	}
+
+	#[test]
+	fn file_mtime_header()
+	{
+		assert_eq!(file_mtime(MtimeSource::Header, Some(at(12)), Some(at(8))), at(12));
+		assert_eq!(file_mtime(MtimeSource::Header, None, Some(at(8))), at(8));
+	}
+
+	#[test]
+	fn file_mtime_id()
+	{
+		assert_eq!(file_mtime(MtimeSource::Id, Some(at(12)), Some(at(8))), at(8));
+		assert_eq!(file_mtime(MtimeSource::Id, Some(at(12)), None), at(12));
+	}
+
+	#[test]
+	fn file_mtime_min()
+	{
+		assert_eq!(file_mtime(MtimeSource::Min, Some(at(12)), Some(at(8))), at(8));
+		assert_eq!(file_mtime(MtimeSource::Min, Some(at(6)), Some(at(8))), at(6));
+		assert_eq!(file_mtime(MtimeSource::Min, None, Some(at(8))), at(8));
+	}
+
+	#[test]
+	fn file_mtime_max()
+	{
+		assert_eq!(file_mtime(MtimeSource::Max, Some(at(12)), Some(at(8))), at(12));
+		assert_eq!(file_mtime(MtimeSource::Max, Some(at(6)), Some(at(8))), at(8));
+		assert_eq!(file_mtime(MtimeSource::Max, Some(at(12)), None), at(12));
+	}
+
+	/// Answer the n-th request with `response(n, head)` and close the connection, keeping the request heads
+	async fn mock(response: fn(usize, &str) -> Vec<u8>) -> (http::Url, Arc<Mutex<Vec<String>>>)
+	{
+		use tokio::io::AsyncReadExt;
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/webcam/Cam/", listener.local_addr().unwrap()).parse().unwrap();
+		let requests = Arc::new(Mutex::new(Vec::new()));
+		tokio::spawn({
+			let requests = requests.clone();
+			async move {
+				while let Ok((mut stream, _)) = listener.accept().await {
+					let mut buf = [0; 4096];
+					let mut len = 0;
+					while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+						match stream.read(&mut buf[len..]).await {
+							Ok(0) | Err(_) => break,
+							Ok(read) => len += read,
+						}
+					}
+					let head = String::from_utf8_lossy(&buf[..len]).into_owned();
+					let n = {
+						let mut requests = requests.lock().unwrap();
+						requests.push(head.clone());
+						requests.len() - 1
+					};
+					stream.write_all(&response(n, &head)).await.ok();
+					stream.shutdown().await.ok();
+				}
+			}
+		});
+		(url, requests)
+	}
+
+	fn image(body_len: usize, sent: usize) -> Vec<u8>
+	{
+		let mut resp = format!("HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body_len)
+			.into_bytes();
+		resp.resize(resp.len() + sent, 0xff);
+		resp
+	}
+
+	/// Download the image `img` of `url` into a new temporary folder
+	async fn fetch(url: &http::Url, args: &[&str]) -> (tempfile::TempDir, Client, PathBuf, Result<Download>)
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let (client, path, res) = fetch_into(dir.path(), url, args).await;
+		(dir, client, path, res)
+	}
+
+	/// Download the image `img` of `url` into `dir`, which may have it already
+	async fn fetch_into(dir: &Path, url: &http::Url, args: &[&str]) -> (Client, PathBuf, Result<Download>)
+	{
+		let mut all = vec![dir.to_str().unwrap(), url.as_str()];
+		all.extend(args);
+		let opt = opt(&all);
+		let client = build_client(&opt).await.unwrap();
+		let path = dir.join("img_hu.jpg");
+		let res = download_retrying(&opt, &client, &ProgressBar::hidden(), "img", &url.join("img_hu.jpg").unwrap(), &path, &DirCache::default()).await;
+		(client, path, res)
+	}
+
+	/// Folder with the image `img` saved at `mtime`
+	fn archived(mtime: DateTime<Utc>) -> tempfile::TempDir
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("img_hu.jpg");
+		std::fs::write(&path, b"old").unwrap();
+		let tv = TimeVal::milliseconds(mtime.timestamp_millis());
+		nix::sys::stat::utimes(&path, &tv, &tv).unwrap();
+		dir
+	}
+
+	/// The If-Modified-Since of the n-th request
+	fn if_modified_since(requests: &Mutex<Vec<String>>, n: usize) -> Option<DateTime<Utc>>
+	{
+		requests.lock().unwrap()[n].lines()
+			.find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("if-modified-since")))
+			.and_then(|(_, value)| DateTime::parse_from_rfc2822(value.trim()).ok())
+			.map(|time| time.with_timezone(&Utc))
+	}
+
+	fn modified_image(last_modified: &str) -> Vec<u8>
+	{
+		format!("HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 3\r\nLast-Modified: {}\r\nConnection: close\r\n\r\nnew",
+			last_modified).into_bytes()
+	}
+
+	fn budget_left(client: &Client) -> u32
+	{
+		client.retries.budget.as_ref().unwrap().load(Ordering::Relaxed)
+	}
+
+	#[tokio::test]
+	async fn connect_failures_use_connect_retries()
+	{
+		// nothing listens on a port right after it was released
+		let url: http::Url = {
+			let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+			format!("http://{}/webcam/Cam/", listener.local_addr().unwrap()).parse().unwrap()
+		};
+		let (_dir, client, _, res) = fetch(&url, &["--connect-retries", "2", "--body-retries", "0", "--retry-budget", "10"]).await;
+		let err = res.err().unwrap();
+		assert!(matches!(Failure::of(&err), Some(Failure::Connect)), "{:#}", err);
+		assert_eq!(budget_left(&client), 8);
+	}
+
+	#[tokio::test]
+	async fn body_failures_use_body_retries()
+	{
+		let (url, requests) = mock(|_, _| image(100, 50)).await;
+		let (_dir, client, _, res) = fetch(&url, &["--connect-retries", "5", "--body-retries", "1", "--retry-budget", "10"]).await;
+		let err = res.err().unwrap();
+		assert!(matches!(Failure::of(&err), Some(Failure::Body)), "{:#}", err);
+		assert_eq!(requests.lock().unwrap().len(), 2);
+		assert_eq!(budget_left(&client), 9);
+	}
+
+	#[tokio::test]
+	async fn retries_start_over_on_success()
+	{
+		let (url, requests) = mock(|n, _| match n {
+			0 => image(100, 50),
+			_ => image(100, 100),
+		}).await;
+		let (_dir, _, path, res) = fetch(&url, &["--body-retries", "1"]).await;
+		assert!(matches!(res, Ok(Download { status: Status::Downloaded, size: 100, .. })));
+		assert_eq!(requests.lock().unwrap().len(), 2);
+		assert_eq!(std::fs::metadata(&path).unwrap().len(), 100);
+	}
+
+	#[tokio::test]
+	async fn other_failures_are_not_retried()
+	{
+		let (url, requests) = mock(|_, _| b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()).await;
+		let (_dir, _, _, res) = fetch(&url, &["--connect-retries", "3", "--body-retries", "3"]).await;
+		let err = res.err().unwrap();
+		assert!(Failure::of(&err).is_none() && is_not_found(&err), "{:#}", err);
+		assert_eq!(requests.lock().unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn failed_token_refresh_is_not_retried()
+	{
+		// the first token is already due for a refresh by the time of the download
+		let (url, requests) = mock(|n, _| match n {
+			0 => {
+				let body = r#"{"access_token":"a","expires_in":1}"#;
+				format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+			},
+			_ => b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+		}).await;
+		let token_url = url.join("token").unwrap();
+		let (_dir, _, _, res) = fetch(&url, &["--oauth-token-url", token_url.as_str(), "--client-id", "id", "--client-secret", "secret",
+			"--connect-retries", "3", "--body-retries", "3"]).await;
+		let err = res.err().unwrap();
+		assert!(err.is::<TokenRefresh>() && Failure::of(&err).is_none(), "{:#}", err);
+		assert!(err.to_string().starts_with("token refresh failed: "), "{}", err);
+		assert_eq!(requests.lock().unwrap().len(), 2);
+	}
+
+	#[tokio::test]
+	async fn short_body_leaves_no_image()
+	{
+		let (url, _) = mock(|_, _| image(100, 50)).await;
+		let (_dir, _, path, res) = fetch(&url, &["--body-retries", "0"]).await;
+		let err = res.err().unwrap();
+		assert!(matches!(Failure::of(&err), Some(Failure::Body)), "{:#}", err);
+		assert!(!path.exists());
+		assert!(!part_file(&path).exists());
+	}
+
+	#[tokio::test]
+	async fn complete_body_is_kept()
+	{
+		let (url, _) = mock(|_, _| image(100, 100)).await;
+		let (_dir, _, path, res) = fetch(&url, &["--body-retries", "0"]).await;
+		assert!(matches!(res, Ok(Download { status: Status::Downloaded, size: 100, .. })));
+		assert_eq!(std::fs::metadata(&path).unwrap().len(), 100);
+		assert!(!part_file(&path).exists());
+	}
+
+	#[tokio::test]
+	async fn clock_skew_is_added_to_if_modified_since()
+	{
+		let (url, requests) = mock(|_, _| b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_vec()).await;
+		let dir = archived(at(12));
+		let (_, _, res) = fetch_into(dir.path(), &url, &["--max-clock-skew", "30s"]).await;
+		assert!(matches!(res, Ok(Download { status: Status::Exists, .. })));
+		assert_eq!(if_modified_since(&requests, 0), Some(at(12) + chrono::Duration::seconds(30)));
+	}
+
+	#[tokio::test]
+	async fn last_modified_within_clock_skew_is_unchanged()
+	{
+		let (url, _) = mock(|_, _| modified_image("Sun, 01 May 2022 12:00:20 GMT")).await;
+		let dir = archived(at(12));
+		let (_, path, res) = fetch_into(dir.path(), &url, &["--max-clock-skew", "30s"]).await;
+		assert!(matches!(res, Ok(Download { status: Status::Exists, .. })));
+		assert_eq!(std::fs::read(&path).unwrap(), b"old");
+
+		// past the skew it is an update
+		let (url, _) = mock(|_, _| modified_image("Sun, 01 May 2022 12:01:00 GMT")).await;
+		let (_, path, res) = fetch_into(dir.path(), &url, &["--max-clock-skew", "30s"]).await;
+		assert!(matches!(res, Ok(Download { status: Status::Downloaded, .. })));
+		assert_eq!(std::fs::read(&path).unwrap(), b"new");
+	}
+
+	#[tokio::test]
+	async fn pretend_modified_since_ignores_clock_skew()
+	{
+		let (url, requests) = mock(|_, _| modified_image("Sun, 01 May 2022 12:00:20 GMT")).await;
+		let dir = archived(at(12));
+		let (_, _, res) = fetch_into(dir.path(), &url, &["--max-clock-skew", "30s", "--pretend-modified-since", "2022-05-01T06:00:00Z"]).await;
+		assert!(matches!(res, Ok(Download { status: Status::Downloaded, .. })));
+		assert_eq!(if_modified_since(&requests, 0), Some(at(6)));
+	}
+
+	/// Run one pass against `url` into `dir`
+	async fn scrape_once(dir: &Path, url: &http::Url, args: &[&str]) -> Result<()>
+	{
+		let mut all = vec![dir.to_str().unwrap(), url.as_str(), "--summary-format", "quiet"];
+		all.extend(args);
+		let opt = Arc::new(opt(&all));
+		let retries = Arc::new(Retries::new(&opt));
+		scrape(opt, Arc::default(), None, retries, CancellationToken::new()).await
+	}
+
+	#[tokio::test]
+	async fn empty_listing_only_fails_for_exit_on_empty_listing()
+	{
+		let (url, _) = mock(|_, _| {
+			let body = r#"{"thumbs":[]}"#;
+			format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).into_bytes()
+		}).await;
+		let dir = tempfile::tempdir().unwrap();
+		scrape_once(dir.path(), &url, &[]).await.unwrap();
+		let err = scrape_once(dir.path(), &url, &["--watch", "1m", "--exit-on-empty-listing", "2"]).await.err().unwrap();
+		assert!(err.is::<EmptyListing>(), "{:#}", err);
+	}
+
+	#[tokio::test]
+	async fn discarded_downloads_are_left_out_of_the_manifest()
+	{
+		let (url, _) = mock(|_, head| {
+			if head.contains("list.php") {
+				let body = match head.contains("img=2022") {
+					true => r#"{"thumbs":[]}"#,
+					false => r#"{"thumbs":["2022/05/01/1200_la.jpg","2022/05/01/1300_la.jpg"]}"#,
+				};
+				format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).into_bytes()
+			} else if head.contains("1200_hu.jpg") {
+				image(3, 3)
+			} else {
+				b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+			}
+		}).await;
+		let dir = tempfile::tempdir().unwrap();
+		let manifest = dir.path().join("SHA256SUMS");
+		let res = scrape_once(dir.path(), &url, &["--atomic-archive", "--checksum-manifest", manifest.to_str().unwrap()]).await;
+		assert!(res.is_ok(), "{:#}", res.unwrap_err());
+		assert!(!dir.path().join("2022/05/01/1200_hu.jpg").exists());
+		assert_eq!(fs::read_to_string(&manifest).await.unwrap(), "");
+	}
+
+	#[tokio::test]
+	async fn calibration_downloads_are_retried_and_metered()
+	{
+		let (url, _) = mock(|n, head| match head.contains("list.php") {
+			true => {
+				let body = r#"{"thumbs":["2022/05/01/1200_la.jpg"]}"#;
+				format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).into_bytes()
+			},
+			// the first download breaks off
+			false => image(4, if n == 1 { 2 } else { 4 }),
+		}).await;
+		let dir = tempfile::tempdir().unwrap();
+		let opt = opt(&[dir.path().to_str().unwrap(), url.as_str(), "--body-retries", "1", "--retry-budget", "10"]);
+		let client = build_client(&opt).await.unwrap();
+		let res = calibrate(&opt, &client, &base_url(&opt.url), &ProgressBar::hidden()).await;
+		assert!(res.is_ok(), "{:#}", res.unwrap_err());
+		assert_eq!(budget_left(&client), 9);
+		assert!(client.meter.average() > 0.0);
+	}
+
+	#[tokio::test]
+	async fn disposition_names_are_kept_apart_and_remembered()
+	{
+		let (url, requests) = mock(|_, _| {
+			let body = b"\xff\xd8\xff\xd9";
+			let mut resp = format!("HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\
+				Content-Disposition: attachment; filename=\"snapshot.jpg\"\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+			resp.extend_from_slice(body);
+			resp
+		}).await;
+		let dir = tempfile::tempdir().unwrap();
+		let opt = opt(&[dir.path().to_str().unwrap(), url.as_str(), "--use-content-disposition", "--mtime-source", "id"]);
+		let dirs = DirCache::default();
+
+		let mut paths = Vec::new();
+		for img in ["2022/05/01/1200", "2022/05/01/1210"] {
+			let client = build_client(&opt).await.unwrap();
+			let id_path = image_file(&opt, &(img.to_owned() + &opt.suffix_full));
+			let url = image_location(&opt, &image_base(&opt), img).unwrap();
+			let download = download(&opt, &client, img, &url, &id_path, &dirs).await.unwrap();
+			assert!(matches!(download.status, Status::Downloaded));
+			paths.push(download.path);
+		}
+		assert_eq!(paths[0], dir.path().join("2022/05/01/1200_snapshot.jpg"));
+		assert_eq!(paths[1], dir.path().join("2022/05/01/1210_snapshot.jpg"));
+
+		// a new run finds the image under its recorded name instead of downloading it again
+		let client = build_client(&opt).await.unwrap();
+		let img = "2022/05/01/1200";
+		let id_path = image_file(&opt, &(img.to_owned() + &opt.suffix_full));
+		let url = image_location(&opt, &image_base(&opt), img).unwrap();
+		let download = download(&opt, &client, img, &url, &id_path, &dirs).await.unwrap();
+		assert!(matches!(download.status, Status::Exists));
+		assert_eq!(download.path, paths[0]);
+		assert_eq!(requests.lock().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn file_mtime_falls_back_to_now()
+	{
+		let before = Utc::now();
+		for source in [MtimeSource::Header, MtimeSource::Id, MtimeSource::Min, MtimeSource::Max] {
+			let time = file_mtime(source, None, None);
+			assert!(time >= before && time <= Utc::now());
+		}
+	}
 }
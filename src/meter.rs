@@ -0,0 +1,65 @@
+use std::{
+	collections::VecDeque,
+	sync::{atomic::{AtomicU64, Ordering}, Mutex},
+	time::{Duration, Instant},
+};
+
+
+/// Bytes received over a run, with the most recent ones kept per second for a windowed rate
+pub struct Meter {
+	started: Instant,
+	window: Duration,
+	total: AtomicU64,
+	/// Bytes received in each second since `started` that is still within the window
+	seconds: Mutex<VecDeque<(u64, u64)>>,
+}
+
+impl Meter
+{
+	pub fn new(window: Duration) -> Self
+	{
+		Meter {
+			started: Instant::now(),
+			window: window.max(Duration::from_secs(1)),
+			total: AtomicU64::new(0),
+			seconds: Mutex::default(),
+		}
+	}
+
+	pub fn record(&self, bytes: u64)
+	{
+		self.total.fetch_add(bytes, Ordering::Relaxed);
+		let now = self.started.elapsed().as_secs();
+		let mut seconds = self.seconds.lock().unwrap();
+		match seconds.back_mut() {
+			Some((second, sum)) if *second == now => *sum += bytes,
+			_ => seconds.push_back((now, bytes)),
+		}
+		while seconds.front().is_some_and(|(second, _)| second + self.window.as_secs() <= now) {
+			seconds.pop_front();
+		}
+	}
+
+	/// Bytes per second since the start
+	pub fn average(&self) -> f64
+	{
+		self.total.load(Ordering::Relaxed) as f64 / self.started.elapsed().as_secs_f64()
+	}
+
+	/// Bytes per second over the last window, or since the start if that is shorter
+	pub fn recent(&self) -> f64
+	{
+		let elapsed = self.started.elapsed();
+		let now = elapsed.as_secs();
+		let bytes: u64 = self.seconds.lock().unwrap().iter()
+			.filter(|(second, _)| second + self.window.as_secs() > now)
+			.map(|(_, bytes)| bytes)
+			.sum();
+		bytes as f64 / elapsed.min(self.window).as_secs_f64()
+	}
+
+	pub fn window(&self) -> Duration
+	{
+		self.window
+	}
+}
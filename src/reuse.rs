@@ -0,0 +1,64 @@
+use std::fmt::{self, Write};
+
+use tracing::{
+	field::{Field, Visit},
+	span, Event, Level, Metadata, Subscriber,
+};
+
+
+/// Modules of hyper that tell when a connection is opened and when a pooled one is reused
+const TARGETS: &[&str] = &["hyper::client::pool", "hyper::client::connect::http"];
+
+/// Prints the connection events of hyper, for --debug-connections
+pub struct Printer;
+
+impl Printer
+{
+	pub fn install() -> anyhow::Result<()>
+	{
+		tracing::subscriber::set_global_default(Printer)
+			.map_err(|err| anyhow::anyhow!("failed to install connection debug output: {}", err))
+	}
+}
+
+impl Subscriber for Printer
+{
+	fn enabled(&self, meta: &Metadata) -> bool
+	{
+		*meta.level() <= Level::DEBUG && TARGETS.contains(&meta.target())
+	}
+
+	fn new_span(&self, _: &span::Attributes) -> span::Id
+	{
+		// spans carry nothing of interest here
+		span::Id::from_u64(1)
+	}
+
+	fn record(&self, _: &span::Id, _: &span::Record) {}
+
+	fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+	fn event(&self, event: &Event)
+	{
+		let mut line = String::from("connection: ");
+		event.record(&mut Message(&mut line));
+		eprintln!("{}", line);
+	}
+
+	fn enter(&self, _: &span::Id) {}
+
+	fn exit(&self, _: &span::Id) {}
+}
+
+/// Appends the message of an event, like `reuse idle connection for ("http", host)` or `connecting to addr`
+struct Message<'a>(&'a mut String);
+
+impl Visit for Message<'_>
+{
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug)
+	{
+		if field.name() == "message" {
+			write!(self.0, "{:?}", value).ok();
+		}
+	}
+}
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, Context};
+use tokio::fs;
+
+
+/// Buffer for listing pages, kept in memory or spilled to files in a directory
+pub struct Pages {
+	dir: Option<PathBuf>,
+	mem: Vec<Vec<String>>,
+	count: usize,
+}
+
+impl Pages
+{
+	pub async fn new(dir: Option<PathBuf>) -> Result<Self>
+	{
+		if let Some(dir) = &dir {
+			fs::create_dir_all(dir).await
+				.with_context(|| format!("failed to create spill directory {}", dir.display()))?;
+		}
+		Ok(Pages { dir, mem: Vec::new(), count: 0 })
+	}
+
+	pub fn len(&self) -> usize
+	{
+		self.count
+	}
+
+	pub async fn push(&mut self, page: Vec<String>) -> Result<()>
+	{
+		match &self.dir {
+			Some(dir) => {
+				let path = dir.join(self.count.to_string());
+				fs::write(&path, page.join("\n")).await
+					.with_context(|| format!("failed to spill page to {}", path.display()))?;
+			},
+			None => self.mem.push(page),
+		}
+		self.count += 1;
+		Ok(())
+	}
+
+	/// Take out the page at `index`, leaving an empty one
+	pub async fn take(&mut self, index: usize) -> Result<Vec<String>>
+	{
+		let dir = match &self.dir {
+			Some(dir) => dir,
+			None => return Ok(std::mem::take(&mut self.mem[index])),
+		};
+
+		let path = dir.join(index.to_string());
+		let data = fs::read_to_string(&path).await
+			.with_context(|| format!("failed to read spilled page {}", path.display()))?;
+		fs::remove_file(&path).await.ok();
+		Ok(data.lines().map(str::to_owned).collect())
+	}
+}
+
+impl Drop for Pages
+{
+	fn drop(&mut self)
+	{
+		if let Some(dir) = &self.dir {
+			std::fs::remove_dir_all(dir).ok();
+		}
+	}
+}
@@ -0,0 +1,70 @@
+use std::{
+	fs, io,
+	path::Path,
+};
+
+use anyhow::{Result, Context};
+use nix::sys::time::{TimeVal, TimeValLike};
+
+
+/// Move all files from `staging` into `target` and remove `staging`, returning the number of files moved
+pub fn commit(staging: &Path, target: &Path) -> Result<u64>
+{
+	let count = move_tree(staging, target)?;
+	fs::remove_dir_all(staging)
+		.with_context(|| format!("failed to remove staging directory {}", staging.display()))?;
+	Ok(count)
+}
+
+/// Remove `staging` with everything in it
+pub fn discard(staging: &Path) -> Result<()>
+{
+	match fs::remove_dir_all(staging) {
+		Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err)
+			.with_context(|| format!("failed to remove staging directory {}", staging.display())),
+		_ => Ok(()),
+	}
+}
+
+fn move_tree(src: &Path, dst: &Path) -> Result<u64>
+{
+	let entries = match fs::read_dir(src) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+		Err(err) => return Err(err)
+			.with_context(|| format!("failed to read directory {}", src.display())),
+	};
+	fs::create_dir_all(dst)
+		.with_context(|| format!("failed to create directory {}", dst.display()))?;
+
+	let mut count = 0;
+	for entry in entries {
+		let entry = entry?;
+		let (from, to) = (entry.path(), dst.join(entry.file_name()));
+		if entry.file_type()?.is_dir() {
+			count += move_tree(&from, &to)?;
+		} else {
+			move_file(&from, &to)
+				.with_context(|| format!("failed to move {} to {}", from.display(), to.display()))?;
+			count += 1;
+		}
+	}
+	Ok(count)
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()>
+{
+	match fs::rename(from, to) {
+		Err(err) if err.raw_os_error() == Some(nix::libc::EXDEV) => (),
+		res => return Ok(res?),
+	}
+
+	// different file systems, so copy and carry over the modification time
+	let mtime = fs::metadata(from)?.modified()?
+		.duration_since(std::time::UNIX_EPOCH)?;
+	fs::copy(from, to)?;
+	let tv = TimeVal::milliseconds(mtime.as_millis() as i64);
+	nix::sys::stat::utimes(to, &tv, &tv)?;
+	fs::remove_file(from)?;
+	Ok(())
+}
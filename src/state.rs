@@ -0,0 +1,39 @@
+use std::path::{Path, PathBuf};
+
+use tokio::{fs, io::AsyncWriteExt};
+
+
+/// Replace the file at `path` with `data` through a temporary file next to it,
+/// so that a crash leaves either the old or the new content behind
+pub async fn write(path: &Path, data: impl AsRef<[u8]>) -> std::io::Result<()>
+{
+	let tmp = tmp_file(path);
+	let mut file = fs::File::create(&tmp).await?;
+	file.write_all(data.as_ref()).await?;
+	file.sync_all().await?;
+	fs::rename(&tmp, path).await
+}
+
+fn tmp_file(path: &Path) -> PathBuf
+{
+	let mut name = path.file_name().unwrap_or_default().to_owned();
+	name.push(".tmp");
+	path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[tokio::test]
+	async fn write_replaces_content()
+	{
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.json");
+		write(&path, "old").await.unwrap();
+		write(&path, "new").await.unwrap();
+		assert_eq!(fs::read_to_string(&path).await.unwrap(), "new");
+		assert!(fs::metadata(tmp_file(&path)).await.is_err());
+	}
+}
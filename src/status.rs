@@ -0,0 +1,62 @@
+use std::{
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, Context};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+
+
+/// Counters of the running session, served as JSON on the status port
+#[derive(Serialize, JsonSchema, Default, Clone)]
+pub struct Stats {
+	pub downloaded: u64,
+	pub exists: u64,
+	pub failed: u64,
+	/// Time of the last image that was downloaded or found unchanged
+	pub last_success: Option<DateTime<Utc>>,
+	/// Time and message of the last failed image
+	pub last_error: Option<(DateTime<Utc>, String)>,
+}
+
+/// Bind the status port and answer every request on it with the current stats
+pub async fn serve(addr: SocketAddr, stats: Arc<Mutex<Stats>>) -> Result<()>
+{
+	let listener = TcpListener::bind(addr).await
+		.with_context(|| format!("failed to bind status port {}", addr))?;
+
+	tokio::spawn(async move {
+		while let Ok((stream, _)) = listener.accept().await {
+			let body = serde_json::to_vec(&*stats.lock().unwrap());
+			if let Ok(body) = body {
+				tokio::spawn(respond(stream, body));
+			}
+		}
+	});
+	Ok(())
+}
+
+async fn respond(mut stream: TcpStream, body: Vec<u8>) -> Result<()>
+{
+	// the request itself does not matter, every path gets the stats
+	let mut buf = [0; 1024];
+	let mut len = 0;
+	while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+		match stream.read(&mut buf[len..]).await? {
+			0 => break,
+			n => len += n,
+		}
+	}
+
+	let head = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+	stream.write_all(head.as_bytes()).await?;
+	stream.write_all(&body).await?;
+	stream.shutdown().await?;
+	Ok(())
+}
@@ -0,0 +1,107 @@
+use std::{
+	collections::VecDeque,
+	sync::Mutex,
+	time::Duration,
+};
+
+use tokio::sync::watch;
+
+
+/// Lowers the number of concurrent downloads while they fail too often, and raises it again once they succeed
+pub struct Throttle {
+	workers: usize,
+	max_error_rate: f64,
+	state: Mutex<State>,
+	/// Pinged whenever a slot frees up or the limit changes
+	changed: watch::Sender<()>,
+}
+
+struct State {
+	/// Downloads allowed at once
+	limit: usize,
+	/// Downloads currently running
+	active: usize,
+	/// Recent outcomes since the limit last changed, `true` for failures
+	window: VecDeque<bool>,
+}
+
+/// Outcomes a change of the limit is based on
+const WINDOW: usize = 20;
+
+/// Delay before each download per throttled worker
+const DELAY: Duration = Duration::from_millis(500);
+
+/// Permission to download, given back on drop
+pub struct Slot<'a>(&'a Throttle);
+
+impl Drop for Slot<'_>
+{
+	fn drop(&mut self)
+	{
+		self.0.state.lock().unwrap().active -= 1;
+		self.0.changed.send_replace(());
+	}
+}
+
+impl Throttle
+{
+	pub fn new(workers: usize, max_error_rate: f64) -> Self
+	{
+		Throttle {
+			workers,
+			max_error_rate,
+			state: Mutex::new(State { limit: workers, active: 0, window: VecDeque::new() }),
+			changed: watch::channel(()).0,
+		}
+	}
+
+	/// Wait for a free slot, pausing longer the more workers are throttled
+	pub async fn acquire(&self) -> Slot<'_>
+	{
+		// subscribed before checking, so no change in between is missed
+		let mut changed = self.changed.subscribe();
+		let throttled = loop {
+			{
+				let mut state = self.state.lock().unwrap();
+				if state.active < state.limit {
+					state.active += 1;
+					break self.workers - state.limit;
+				}
+			}
+			changed.changed().await.ok();
+		};
+		let slot = Slot(self);
+		if throttled > 0 {
+			tokio::time::sleep(DELAY * throttled as u32).await;
+		}
+		slot
+	}
+
+	/// Record the outcome of a download, returning the new limit if that changed it
+	pub fn record(&self, failed: bool) -> Option<usize>
+	{
+		let mut state = self.state.lock().unwrap();
+		state.window.push_back(failed);
+		if state.window.len() > WINDOW {
+			state.window.pop_front();
+		}
+
+		let len = state.window.len();
+		let failures = state.window.iter().filter(|&&failed| failed).count();
+		let limit = if len >= WINDOW / 2 && failures as f64 / len as f64 > self.max_error_rate {
+			state.limit.saturating_sub(1).max(1)
+		} else if len == WINDOW && failures == 0 {
+			(state.limit + 1).min(self.workers)
+		} else {
+			state.limit
+		};
+		if limit == state.limit {
+			return None;
+		}
+		state.limit = limit;
+		state.window.clear();
+		drop(state);
+		self.changed.send_replace(());
+		Some(limit)
+	}
+}
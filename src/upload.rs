@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use anyhow::{Result, Context};
+use s3::{creds::Credentials, Bucket, Region};
+
+
+/// Bucket of an S3-compatible store that downloaded images are copied to
+pub struct Sink {
+	bucket: Box<Bucket>,
+	prefix: String,
+}
+
+impl Sink
+{
+	/// Credentials and, without `endpoint`, the region come from the standard AWS variables
+	pub fn new(name: &str, prefix: Option<&str>, endpoint: Option<&str>) -> Result<Self>
+	{
+		let region = match endpoint {
+			Some(endpoint) => Region::Custom {
+				region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+				endpoint: endpoint.to_owned(),
+			},
+			None => Region::from_default_env().context("failed to get the S3 region from AWS_REGION")?,
+		};
+		let credentials = Credentials::default().context("failed to get S3 credentials")?;
+		let mut bucket = Bucket::new(name, region, credentials)
+			.with_context(|| format!("failed to set up S3 bucket {}", name))?;
+		// stores of other vendors rarely have a host name per bucket
+		if endpoint.is_some() {
+			bucket = bucket.with_path_style();
+		}
+
+		let prefix = match prefix {
+			Some(prefix) if !prefix.is_empty() && !prefix.ends_with('/') => format!("{}/", prefix),
+			prefix => prefix.unwrap_or_default().to_owned(),
+		};
+		Ok(Sink { bucket, prefix })
+	}
+
+	/// Upload the file at `path` as `key` below the prefix with `x-amz-meta-*` headers of `meta`;
+	/// it is read in chunks, and sent in parts if larger than one
+	pub async fn upload(&self, path: &Path, key: &str, content_type: &str, meta: &[(&str, String)]) -> Result<()>
+	{
+		let mut bucket = (*self.bucket).clone();
+		for (name, value) in meta {
+			bucket.add_header(&format!("x-amz-meta-{}", name), value);
+		}
+		let key = format!("{}{}", self.prefix, key);
+
+		let mut file = tokio::fs::File::open(path).await
+			.with_context(|| format!("failed to open {}", path.display()))?;
+		let resp = bucket.put_object_stream_with_content_type(&mut file, &key, content_type).await
+			.with_context(|| format!("failed to upload {} to S3", key))?;
+		anyhow::ensure!(resp.status_code() < 300, "failed to upload {} to S3: status {}", key, resp.status_code());
+		Ok(())
+	}
+}